@@ -0,0 +1,76 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use loki_api::logproto as loki;
+use loki_api::prost::Message;
+use std::time::Duration;
+use std::time::SystemTime;
+use tracing_loki::bench::EncodeBenchmark;
+
+fn streams() -> Vec<(String, Vec<(SystemTime, String)>)> {
+    let now = SystemTime::now();
+    ["trace", "debug", "info", "warn", "error"]
+        .iter()
+        .map(|level| {
+            let labels = format!(r#"{{host="mine",level="{}"}}"#, level);
+            let entries = (0..200)
+                .map(|i| {
+                    let line = format!(
+                        r#"{{"message":"request handled","status":200,"id":{},"_spans":["request"],"_target":"app","_module_path":"app","_file":"src/main.rs","_line":42}}"#,
+                        i,
+                    );
+                    (now + Duration::from_micros(i), line)
+                })
+                .collect();
+            (labels, entries)
+        })
+        .collect()
+}
+
+/// The previous approach of building a `PushRequest` by copying all lines and
+/// labels, encoding it with prost and copying the compressed result.
+fn encode_prost(
+    streams: &[(String, Vec<(SystemTime, String)>)],
+    encoded: &mut Vec<u8>,
+    snappy: &mut Vec<u8>,
+) -> Vec<u8> {
+    let request = loki::PushRequest {
+        streams: streams
+            .iter()
+            .map(|(labels, entries)| loki::StreamAdapter {
+                labels: labels.clone(),
+                entries: entries
+                    .iter()
+                    .map(|(timestamp, line)| loki::EntryAdapter {
+                        timestamp: Some((*timestamp).into()),
+                        line: line.clone(),
+                    })
+                    .collect(),
+                hash: 0,
+            })
+            .collect(),
+    };
+    encoded.clear();
+    request.encode(encoded).unwrap();
+    snappy.resize(snap::raw::max_compress_len(encoded.len()), 0);
+    let len = snap::raw::Encoder::new().compress(encoded, snappy).unwrap();
+    snappy[..len].to_owned()
+}
+
+fn encode(c: &mut Criterion) {
+    let streams = streams();
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("prost", |b| {
+        let mut encoded = Vec::new();
+        let mut snappy = Vec::new();
+        b.iter(|| encode_prost(&streams, &mut encoded, &mut snappy))
+    });
+    group.bench_function("direct", |b| {
+        let mut encoder = EncodeBenchmark::new();
+        b.iter(|| encoder.encode(&streams))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
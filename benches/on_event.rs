@@ -0,0 +1,254 @@
+//! Benchmarks of the time an event spends in the [`tracing_loki::Layer`],
+//! compared to a baseline layer that serializes the log line on the calling
+//! thread, like this crate did before formatting moved to the background
+//! task.
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Bencher;
+use criterion::Criterion;
+use std::cmp;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::Dispatch;
+use tracing_subscriber::layer::SubscriberExt;
+use url::Url;
+
+/// The number of events emitted between draining the channel, less than
+/// its capacity so that no events are dropped.
+const BATCH: u64 = 256;
+
+mod baseline {
+    use serde::ser::SerializeMap;
+    use serde::Serialize;
+    use serde::Serializer;
+    use serde_json::Value;
+    use std::fmt;
+    use std::time::SystemTime;
+    use tokio::sync::mpsc;
+    use tracing_core::field::Field;
+    use tracing_core::field::Visit;
+    use tracing_core::span::Attributes;
+    use tracing_core::span::Id;
+    use tracing_core::span::Record;
+    use tracing_core::Event;
+    use tracing_core::Subscriber;
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::registry::LookupSpan;
+
+    struct Fields(serde_json::Map<String, Value>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let value = format!("{:?}", value);
+            self.0.insert(field.name().into(), value.into());
+        }
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            self.0.insert(field.name().into(), value.into());
+        }
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().into(), value.into());
+        }
+    }
+
+    /// Serializes the event's fields directly into a map.
+    struct MapVisitor<'a, M: SerializeMap> {
+        map: &'a mut M,
+        result: Result<(), M::Error>,
+    }
+
+    impl<'a, M: SerializeMap> MapVisitor<'a, M> {
+        fn entry<T: Serialize + ?Sized>(&mut self, field: &Field, value: &T) {
+            if self.result.is_ok() {
+                self.result = self.map.serialize_entry(field.name(), value);
+            }
+        }
+    }
+
+    impl<'a, M: SerializeMap> Visit for MapVisitor<'a, M> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.entry(field, &format_args!("{:?}", value));
+        }
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.entry(field, &value);
+        }
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            self.entry(field, &value);
+        }
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.entry(field, &value);
+        }
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            self.entry(field, &value);
+        }
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.entry(field, value);
+        }
+    }
+
+    struct SerializedEvent<'a> {
+        event: &'a Event<'a>,
+        extra_fields: &'a serde_json::Map<String, Value>,
+        span_fields: serde_json::Map<String, Value>,
+        spans: Vec<&'static str>,
+    }
+
+    impl<'a> Serialize for SerializedEvent<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(None)?;
+            let mut visitor = MapVisitor {
+                map: &mut map,
+                result: Ok(()),
+            };
+            self.event.record(&mut visitor);
+            visitor.result?;
+            for (name, value) in self.extra_fields.iter().chain(&self.span_fields) {
+                map.serialize_entry(name, value)?;
+            }
+            let meta = self.event.metadata();
+            map.serialize_entry("_spans", &self.spans)?;
+            map.serialize_entry("_target", meta.target())?;
+            map.serialize_entry("_module_path", &meta.module_path())?;
+            map.serialize_entry("_file", &meta.file())?;
+            map.serialize_entry("_line", &meta.line())?;
+            map.end()
+        }
+    }
+
+    /// Clones the span fields and serializes the log line in `on_event`.
+    pub struct Layer {
+        pub extra_fields: serde_json::Map<String, Value>,
+        pub sender: mpsc::Sender<(SystemTime, String)>,
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Layer {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut fields = Fields(Default::default());
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+            values.record(extensions.get_mut::<Fields>().unwrap());
+        }
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let timestamp = SystemTime::now();
+            let mut span_fields = serde_json::Map::new();
+            let mut spans = Vec::new();
+            if let Some(scope) = ctx.event_scope(event) {
+                for span in scope.from_root() {
+                    let extensions = span.extensions();
+                    let fields = &extensions.get::<Fields>().unwrap().0;
+                    span_fields.extend(fields.iter().map(|(n, v)| (n.clone(), v.clone())));
+                    spans.push(span.name());
+                }
+            }
+            let line = serde_json::to_string(&SerializedEvent {
+                event,
+                extra_fields: &self.extra_fields,
+                span_fields,
+                spans,
+            })
+            .unwrap();
+            let _ = self.sender.try_send((timestamp, line));
+        }
+    }
+}
+
+/// Measure `emit`, draining the channel every [`BATCH`] events outside of
+/// the measurement.
+fn measure(b: &mut Bencher<'_>, emit: &dyn Fn(), drain: &mut dyn FnMut()) {
+    b.iter_custom(|iters| {
+        let mut elapsed = Duration::ZERO;
+        let mut left = iters;
+        while left > 0 {
+            let n = cmp::min(left, BATCH);
+            let start = Instant::now();
+            for _ in 0..n {
+                emit();
+            }
+            elapsed += start.elapsed();
+            drain();
+            left -= n;
+        }
+        elapsed
+    });
+}
+
+fn scenarios(c: &mut Criterion, name: &str, dispatch: &Dispatch, drain: &mut dyn FnMut()) {
+    tracing::dispatcher::with_default(dispatch, || {
+        let mut group = c.benchmark_group("on_event");
+        group.bench_function(format!("{}/no_spans", name), |b| {
+            let emit = || {
+                tracing::info!(
+                    task = "benchmark",
+                    count = 42,
+                    ratio = 0.5,
+                    "tracing event without spans"
+                )
+            };
+            measure(b, &emit, drain)
+        });
+        let outer = tracing::info_span!(
+            "outer",
+            request_id = "5b6aedb4-e2c1-4ad9-b8a7-3ef92b5c8120",
+            method = "GET",
+            path = "/api/v1/items",
+        );
+        let _outer = outer.enter();
+        let inner = tracing::info_span!("inner", user = "alice", attempt = 3, cached = false);
+        let _inner = inner.enter();
+        group.bench_function(format!("{}/nested_spans", name), |b| {
+            let emit = || {
+                tracing::info!(
+                    task = "benchmark",
+                    count = 42,
+                    ratio = 0.5,
+                    "tracing event in nested spans"
+                )
+            };
+            measure(b, &emit, drain)
+        });
+        group.finish();
+    });
+}
+
+fn on_event(c: &mut Criterion) {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(512);
+    let mut extra_fields = serde_json::Map::new();
+    extra_fields.insert("pid".into(), "1234".into());
+    let layer = baseline::Layer {
+        extra_fields,
+        sender,
+    };
+    let dispatch = Dispatch::new(tracing_subscriber::registry().with(layer));
+    scenarios(c, "baseline", &dispatch, &mut || {
+        while receiver.try_recv().is_ok() {}
+    });
+
+    let (layer, mut task) = tracing_loki::builder()
+        .label("host", "mine")
+        .unwrap()
+        .extra_field("pid", "1234")
+        .unwrap()
+        .build_url(Url::parse("http://127.0.0.1:3100").unwrap())
+        .unwrap();
+    let dispatch = Dispatch::new(tracing_subscriber::registry().with(layer));
+    scenarios(c, "capture", &dispatch, &mut || {
+        tracing_loki::bench::drain(&mut task);
+    });
+}
+
+criterion_group!(benches, on_event);
+criterion_main!(benches);
@@ -0,0 +1,368 @@
+use std::error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::oauth2;
+use super::oauth2::AccessToken;
+use super::Error;
+use super::ErrorI;
+use super::OAuth2;
+
+pub type TokenError = Box<dyn error::Error + Send + Sync>;
+type TokenFn = dyn Fn() -> Result<BearerToken, TokenError> + Send + Sync;
+
+/// A bearer token returned by the function passed to
+/// [`Builder::bearer_token_fn`](crate::Builder::bearer_token_fn).
+///
+/// Strings convert into tokens without an expiry time.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use std::time::SystemTime;
+/// use tracing_loki::BearerToken;
+///
+/// let token = BearerToken::new("secret")
+///     .expires_at(SystemTime::now() + Duration::from_secs(3600));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BearerToken {
+    token: String,
+    expires_at: Option<SystemTime>,
+}
+
+impl BearerToken {
+    /// A token that is used until Loki rejects it.
+    pub fn new<S: Into<String>>(token: S) -> BearerToken {
+        BearerToken {
+            token: token.into(),
+            expires_at: None,
+        }
+    }
+    /// Fetch a new token shortly before `expires_at`.
+    pub fn expires_at(mut self, expires_at: SystemTime) -> BearerToken {
+        self.expires_at = Some(expires_at);
+        self
+    }
+}
+
+impl From<String> for BearerToken {
+    fn from(token: String) -> BearerToken {
+        BearerToken::new(token)
+    }
+}
+
+impl From<&str> for BearerToken {
+    fn from(token: &str) -> BearerToken {
+        BearerToken::new(token)
+    }
+}
+
+/// Where the bearer token comes from.
+pub enum TokenSource {
+    Static(String),
+    Fn(Arc<TokenFn>),
+    /// A file, re-read when its modification time changes.
+    File(PathBuf),
+}
+
+/// A bearer token fetched from a [`TokenSource`].
+pub struct CachedToken {
+    token: String,
+    /// For files, the modification time the token was read at.
+    modified: Option<SystemTime>,
+    /// When to fetch a new token, shortly before it expires.
+    refresh_at: Option<SystemTime>,
+}
+
+/// Credentials attached to each push request, see
+/// [`Builder::basic_auth`](crate::Builder::basic_auth) and
+/// [`Builder::bearer_token`](crate::Builder::bearer_token).
+pub enum Auth {
+    Basic {
+        username: String,
+        password: String,
+    },
+    Bearer {
+        source: TokenSource,
+        /// Held while fetching a token, so that concurrent requests wait for
+        /// it instead of fetching their own.
+        cached: tokio::sync::Mutex<Option<CachedToken>>,
+    },
+    OAuth2 {
+        credentials: OAuth2,
+        /// Held while fetching a token, so that concurrent requests wait for
+        /// it instead of fetching their own.
+        cached: tokio::sync::Mutex<Option<AccessToken>>,
+    },
+}
+
+fn check_token(token: &str) -> Result<(), Error> {
+    reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+        .map(drop)
+        .map_err(|_| Error(ErrorI::InvalidHttpHeaderValue("Authorization".into())))
+}
+
+/// Run `f` on a thread where blocking is allowed, so that it doesn't stall
+/// the background task.
+async fn blocking<T, F>(f: F) -> Result<T, TokenError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, TokenError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Read the token file at `path` and its modification time, unless the
+/// modification time is still `read_at`.
+fn read_token_file(
+    path: &Path,
+    read_at: Option<Option<SystemTime>>,
+) -> Result<Option<(String, Option<SystemTime>)>, TokenError> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    if read_at == Some(modified) {
+        return Ok(None);
+    }
+    let token = fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read token file {}: {}", path.display(), e))?;
+    Ok(Some((token.trim().to_owned(), modified)))
+}
+
+impl Auth {
+    fn bearer(source: TokenSource) -> Auth {
+        Auth::Bearer {
+            source,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+    pub fn basic(username: String, password: String) -> Auth {
+        Auth::Basic { username, password }
+    }
+    pub fn oauth2(credentials: OAuth2) -> Auth {
+        Auth::OAuth2 {
+            credentials,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+    pub fn bearer_static(token: String) -> Result<Auth, Error> {
+        check_token(&token)?;
+        Ok(Auth::bearer(TokenSource::Static(token)))
+    }
+    pub fn bearer_fn<F>(f: F) -> Auth
+    where
+        F: Fn() -> Result<BearerToken, TokenError> + Send + Sync + 'static,
+    {
+        Auth::bearer(TokenSource::Fn(Arc::new(f)))
+    }
+    pub fn bearer_file(path: PathBuf) -> Auth {
+        Auth::bearer(TokenSource::File(path))
+    }
+    /// Whether a rejected request might succeed with fresh credentials.
+    pub fn can_refresh(&self) -> bool {
+        matches!(
+            self,
+            Auth::Bearer {
+                source: TokenSource::Fn(_) | TokenSource::File(_),
+                ..
+            } | Auth::OAuth2 { .. }
+        )
+    }
+    /// The bearer token to send, fetched from the source if there's none
+    /// cached yet, if `refresh` is set, if the token is about to expire or
+    /// if the token file changed.
+    async fn token(&self, now: SystemTime, refresh: bool) -> Result<String, TokenError> {
+        let (source, cached) = match self {
+            Auth::Bearer { source, cached } => (source, cached),
+            _ => unreachable!(),
+        };
+        let mut cached = cached.lock().await;
+        let current = cached.as_ref().filter(|_| !refresh);
+        let fetched = match source {
+            TokenSource::Static(token) => return Ok(token.clone()),
+            TokenSource::Fn(f) => {
+                if let Some(current) = current {
+                    if current.refresh_at.is_none_or(|refresh_at| now < refresh_at) {
+                        return Ok(current.token.clone());
+                    }
+                }
+                let f = f.clone();
+                let BearerToken { token, expires_at } = blocking(move || f()).await?;
+                let expires_in = expires_at.map(|e| e.duration_since(now).unwrap_or_default());
+                CachedToken {
+                    token,
+                    modified: None,
+                    refresh_at: expires_in.and_then(|e| oauth2::refresh_at(now, e)),
+                }
+            }
+            TokenSource::File(path) => {
+                let path = path.clone();
+                let read_at = current.map(|c| c.modified);
+                match blocking(move || read_token_file(&path, read_at)).await? {
+                    Some((token, modified)) => CachedToken {
+                        token,
+                        modified,
+                        refresh_at: None,
+                    },
+                    None => return Ok(current.unwrap().token.clone()),
+                }
+            }
+        };
+        check_token(&fetched.token).map_err(|_| "invalid bearer token")?;
+        let token = fetched.token.clone();
+        *cached = Some(fetched);
+        Ok(token)
+    }
+    /// Add the credentials to `request`, using `client` to request OAuth2
+    /// access tokens.
+    pub async fn apply(
+        &self,
+        request: reqwest::RequestBuilder,
+        client: &reqwest::Client,
+        now: SystemTime,
+        refresh: bool,
+    ) -> Result<reqwest::RequestBuilder, TokenError> {
+        Ok(match self {
+            Auth::Basic { username, password } => request.basic_auth(username, Some(password)),
+            Auth::Bearer { .. } => request.bearer_auth(self.token(now, refresh).await?),
+            Auth::OAuth2 {
+                credentials,
+                cached,
+            } => {
+                let mut cached = cached.lock().await;
+                match &*cached {
+                    Some(token) if !refresh && token.is_fresh(now) => {}
+                    _ => *cached = Some(credentials.fetch(client, now).await?),
+                }
+                request.bearer_auth(&cached.as_ref().unwrap().token)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Auth;
+    use super::BearerToken;
+    use super::TokenError;
+    use crate::test_server;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+    use url::Url;
+
+    #[tokio::test]
+    async fn token_fn() {
+        let now = SystemTime::now();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let auth = Auth::bearer_fn({
+            let calls = calls.clone();
+            move || Ok(format!("token{}", calls.fetch_add(1, Ordering::SeqCst)).into())
+        });
+        assert!(auth.can_refresh());
+        assert_eq!(auth.token(now, false).await.unwrap(), "token0");
+        assert_eq!(auth.token(now, false).await.unwrap(), "token0");
+        assert_eq!(auth.token(now, true).await.unwrap(), "token1");
+        assert_eq!(auth.token(now, false).await.unwrap(), "token1");
+        let invalid = Auth::bearer_fn(|| Ok("a\nb".into()));
+        assert!(invalid.token(now, false).await.is_err());
+        let panicking = Auth::bearer_fn(|| panic!("no token"));
+        assert!(panicking.token(now, false).await.is_err());
+        assert!(Auth::bearer_static("a\nb".into()).is_err());
+        assert!(!Auth::bearer_static("static".into()).unwrap().can_refresh());
+    }
+
+    #[tokio::test]
+    async fn token_fn_expiry() {
+        let now = SystemTime::now();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let auth = Auth::bearer_fn({
+            let calls = calls.clone();
+            move || {
+                let i = calls.fetch_add(1, Ordering::SeqCst);
+                // Each token is valid for an hour after the previous one.
+                let expires_at = now + Duration::from_secs(3600) * (i as u32 + 1);
+                Ok(BearerToken::new(format!("token{}", i)).expires_at(expires_at))
+            }
+        });
+        let token = |secs| auth.token(now + Duration::from_secs(secs), false);
+        assert_eq!(token(0).await.unwrap(), "token0");
+        assert_eq!(token(3539).await.unwrap(), "token0");
+        // Refreshed a minute before it expires.
+        assert_eq!(token(3540).await.unwrap(), "token1");
+        assert_eq!(token(3600).await.unwrap(), "token1");
+        assert_eq!(token(7140).await.unwrap(), "token2");
+    }
+
+    #[tokio::test]
+    async fn token_file() {
+        let now = SystemTime::now();
+        let path = env::temp_dir().join(format!("tracing-loki-token-{}", process::id()));
+        fs::write(&path, "first\n").unwrap();
+        let auth = Auth::bearer_file(path.clone());
+        let first = auth.token(now, false).await.unwrap();
+        fs::write(&path, "second\n").unwrap();
+        // Make sure the modification time changes even on coarse clocks.
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let second = auth.token(now, false).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((&*first, &*second), ("first", "second"));
+        assert!(auth.token(now, true).await.is_err());
+    }
+
+    /// Accept HTTP requests, answering those with an outdated token with
+    /// 401. Returns the `Authorization` header of each request.
+    async fn serve(listener: TcpListener, count: usize) -> Vec<String> {
+        let mut result = Vec::new();
+        for _ in 0..count {
+            let (stream, request) = test_server::accept(&listener).await;
+            let auth = request.header("authorization").unwrap_or("").to_owned();
+            let status = if auth == "Bearer new" {
+                "204 No Content"
+            } else {
+                "401 Unauthorized"
+            };
+            test_server::respond(stream, status, "").await;
+            result.push(auth);
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn refresh_on_unauthorized() {
+        let (listener, url) = test_server::bind().await;
+        let server = tokio::spawn(serve(listener, 2));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (layer, controller, task) = crate::builder()
+            .bearer_token_fn({
+                let calls = calls.clone();
+                move || {
+                    let token = match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => "old",
+                        _ => "new",
+                    };
+                    Ok::<_, TokenError>(token)
+                }
+            })
+            .build_controller_url(Url::parse(&url).unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || tracing::info!(target: "app", "hello"));
+        let task = tokio::spawn(task);
+        controller.shutdown().await;
+        task.await.unwrap();
+        assert_eq!(server.await.unwrap(), ["Bearer old", "Bearer new"]);
+    }
+}
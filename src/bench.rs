@@ -0,0 +1,40 @@
+//! Hooks for the benchmarks, enabled by the `bench` feature. Not part of the
+//! public API.
+
+use super::BackgroundTask;
+use crate::encode::Buffer;
+use bytes::Bytes;
+use std::time::SystemTime;
+
+/// Drop all events queued for `task` without formatting them, returns their
+/// number.
+///
+/// This keeps the channel from filling up while benchmarking the
+/// [`Layer`](crate::Layer) on its own.
+pub fn drain(task: &mut BackgroundTask) -> usize {
+    let mut count = 0;
+    while task.receiver.try_recv().is_ok() {
+        count += 1;
+    }
+    count
+}
+
+/// Encodes push requests like the [`BackgroundTask`] does.
+pub struct EncodeBenchmark(Buffer);
+
+impl EncodeBenchmark {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> EncodeBenchmark {
+        EncodeBenchmark(Buffer::new())
+    }
+    pub fn encode(&mut self, streams: &[(String, Vec<(SystemTime, String)>)]) -> Bytes {
+        self.0.encode(streams.iter().map(|(labels, entries)| {
+            (
+                &labels[..],
+                entries
+                    .iter()
+                    .map(|(timestamp, line)| (*timestamp, &line[..])),
+            )
+        }))
+    }
+}
@@ -0,0 +1,107 @@
+/// Information about the build of the application, see
+/// [`build_info!`](crate::build_info!) and
+/// [`Builder::build_info`](crate::Builder::build_info).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BuildInfo {
+    /// The crate name, with the key `app`.
+    pub name: &'static str,
+    /// The crate version, with the key `version`.
+    pub version: &'static str,
+    /// The git commit, with the key `git_commit`.
+    pub git_commit: Option<&'static str>,
+    /// `"debug"` or `"release"`, with the key `profile`.
+    pub profile: &'static str,
+}
+
+impl BuildInfo {
+    /// The keys and values to attach, skipping unknown values.
+    pub(crate) fn entries(&self) -> Vec<(&'static str, &'static str)> {
+        let mut result = vec![("app", self.name), ("version", self.version)];
+        if let Some(git_commit) = self.git_commit {
+            result.push(("git_commit", git_commit));
+        }
+        result.push(("profile", self.profile));
+        result
+    }
+}
+
+/// Capture the [`BuildInfo`] of the crate calling this macro.
+///
+/// The name and version are taken from Cargo. The git commit is read from
+/// the `GIT_COMMIT` environment variable at compile time, which can be set
+/// by a build script:
+///
+/// ```no_run
+/// // build.rs
+/// use std::process::Command;
+///
+/// fn main() {
+///     let output = Command::new("git").args(["rev-parse", "HEAD"]).output().unwrap();
+///     let commit = String::from_utf8(output.stdout).unwrap();
+///     println!("cargo:rustc-env=GIT_COMMIT={}", commit.trim());
+/// }
+/// ```
+///
+/// The profile is `"debug"` if debug assertions are enabled, `"release"`
+/// otherwise.
+///
+/// # Example
+///
+/// ```
+/// # use tracing_loki::Error;
+/// # fn main() -> Result<(), Error> {
+/// use tracing_loki::ResourceTarget;
+///
+/// let builder = tracing_loki::builder()
+///     .build_info(tracing_loki::build_info!(), ResourceTarget::ExtraField)?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("GIT_COMMIT"),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::BuildInfo;
+
+    #[test]
+    fn build_info() {
+        let info = crate::build_info!();
+        assert_eq!(info.name, "tracing-loki");
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        let profile = if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        };
+        assert_eq!(info.profile, profile);
+        let info = BuildInfo {
+            name: "app",
+            version: "1.0.0",
+            git_commit: None,
+            profile: "release",
+        };
+        assert_eq!(
+            info.entries(),
+            [("app", "app"), ("version", "1.0.0"), ("profile", "release")],
+        );
+        let info = BuildInfo {
+            git_commit: Some("0123abc"),
+            ..info
+        };
+        assert_eq!(info.entries()[2], ("git_commit", "0123abc"));
+    }
+}
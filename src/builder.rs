@@ -1,12 +1,36 @@
+use super::auth::Auth;
+use super::auth::BearerToken;
+use super::auth::TokenError;
+use super::env_config::builder_from_env;
 use super::event_channel;
 use super::BackgroundTask;
 use super::BackgroundTaskController;
+use super::BuildInfo;
+use super::Clock;
 use super::Error;
 use super::ErrorI;
+use super::EventFormatter;
+use super::ExtraFields;
+use super::FieldFilter;
 use super::FormattedLabels;
+use super::Kubernetes;
 use super::Layer;
-use std::collections::hash_map;
-use std::collections::HashMap;
+use super::LineLimits;
+use super::LineOverflow;
+use super::OAuth2;
+use super::RateLimiter;
+use super::RateLimits;
+use super::Resource;
+use super::ResourceTarget;
+use super::TaskOptions;
+use super::TimestampFormat;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use super::TlsVersion;
+use super::TokenBucket;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_core::Level;
 use url::Url;
 
 /// Create a [`Builder`] for constructing a [`Layer`] and its corresponding
@@ -21,8 +45,14 @@ pub fn builder() -> Builder {
     );
     Builder {
         labels: FormattedLabels::new(),
-        extra_fields: HashMap::new(),
+        extra_fields: ExtraFields::new(),
+        field_filter: FieldFilter::new(),
+        line_limits: LineLimits::default(),
+        rate_limits: RateLimits::default(),
+        timestamp_field: None,
+        task_options: TaskOptions::default(),
         http_headers,
+        loki_url: None,
     }
 }
 
@@ -32,28 +62,784 @@ pub fn builder() -> Builder {
 /// See the crate's root documentation for an example.
 #[derive(Clone)]
 pub struct Builder {
-    labels: FormattedLabels,
-    extra_fields: HashMap<String, String>,
-    http_headers: reqwest::header::HeaderMap,
+    pub(crate) labels: FormattedLabels,
+    extra_fields: ExtraFields,
+    field_filter: FieldFilter,
+    line_limits: LineLimits,
+    rate_limits: RateLimits,
+    timestamp_field: Option<(String, TimestampFormat)>,
+    pub(crate) task_options: TaskOptions,
+    pub(crate) http_headers: reqwest::header::HeaderMap,
+    pub(crate) loki_url: Option<Url>,
 }
 
 impl Builder {
+    /// Create a [`Builder`] configured from the environment variables
+    /// starting with `LOKI_`.
+    ///
+    /// See [`Builder::from_env_prefix`] for the variables read.
+    pub fn from_env() -> Result<Builder, Error> {
+        Builder::from_env_prefix("LOKI")
+    }
+    /// Create a [`Builder`] configured from the environment variables
+    /// starting with `prefix` and `_`.
+    ///
+    /// The following variables are read, all of them are optional:
+    ///
+    /// - `<prefix>_URL`: the URL of the Loki server, see
+    ///   [`Builder::loki_url`].
+    /// - `<prefix>_LABELS`: labels as comma-separated `key=value` pairs, like
+    ///   `env=prod,team=x`, see [`Builder::label`].
+    /// - `<prefix>_EXTRA_FIELDS`: extra fields as comma-separated `key=value`
+    ///   pairs, see [`Builder::extra_field`].
+    /// - `<prefix>_HEADERS`: HTTP headers as comma-separated `name=value`
+    ///   pairs, see [`Builder::http_header`].
+    /// - `<prefix>_TENANT`: the tenant ID, sent as the `X-Scope-OrgID` HTTP
+    ///   header.
+    ///
+    /// Values in the lists can't contain commas. The returned builder can be
+    /// configured further.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error naming the variable if a variable
+    /// is not valid unicode, is malformed, or contains an invalid label,
+    /// field or header.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// // Reads `MY_APP_LOKI_URL`, `MY_APP_LOKI_LABELS`, etc.
+    /// let builder = tracing_loki::Builder::from_env_prefix("MY_APP_LOKI")?
+    ///     .label("service", "api")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_env_prefix(prefix: &str) -> Result<Builder, Error> {
+        builder_from_env(prefix, |name| std::env::var(name))
+    }
+    /// Set the URL of the Loki server, like `https://127.0.0.1:3100`, for
+    /// [`Builder::build`] and [`Builder::build_controller`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use url::Url;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .loki_url(Url::parse("http://127.0.0.1:3100").unwrap());
+    /// ```
+    pub fn loki_url(mut self, loki_url: Url) -> Builder {
+        self.loki_url = Some(loki_url);
+        self
+    }
     /// Add a label to the logs sent to Loki through the built `Layer`.
     ///
-    /// Labels are supposed to be closed categories with few possible values.
-    /// For example, `"environment"` with values `"ci"`, `"development"`,
-    /// `"staging"` or `"production"` would work well.
+    /// Labels are supposed to be closed categories with few possible values.
+    /// For example, `"environment"` with values `"ci"`, `"development"`,
+    /// `"staging"` or `"production"` would work well.
+    ///
+    /// For open categories, extra fields are a better fit. See
+    /// [`Builder::extra_field`].
+    ///
+    /// No two labels can share the same name, and the key `"level"` is
+    /// reserved for the log level, unless renamed with
+    /// [`Builder::level_label_name`].
+    ///
+    /// Keys must match `[a-zA-Z_][a-zA-Z0-9_]*` and must not start with
+    /// `__`. Loki's default limits allow keys of up to 1024 bytes, values of
+    /// up to 2048 bytes, and 15 labels per stream, including the level label.
+    /// See [`Builder::sanitize_labels`] for rewriting invalid labels instead.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a key is invalid or a
+    /// duplicate, when the key is the name of the level label, or when the
+    /// value is too long. Building fails if there are too many labels.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .label("environment", "production")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn label<S: Into<String>, T: AsRef<str>>(
+        mut self,
+        key: S,
+        value: T,
+    ) -> Result<Builder, Error> {
+        self.labels.add(key.into(), value.as_ref())?;
+        Ok(self)
+    }
+    /// Rewrite invalid labels added afterwards instead of returning an error.
+    ///
+    /// Invalid characters in keys are replaced with `_`, keys starting with
+    /// a digit are prefixed with `_`, a leading `__` is shortened to `_`, and
+    /// overlong keys and values are truncated. This is useful when labels
+    /// come from configuration files. Duplicate keys, including those
+    /// resulting from the rewriting, are still an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .sanitize_labels(true)
+    ///     // Sent as `k8s_node_name`.
+    ///     .label("k8s.node-name", "node-1")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sanitize_labels(mut self, enabled: bool) -> Builder {
+        self.labels.set_sanitize(enabled);
+        self
+    }
+    /// Set the name of the label carrying the log level, `"level"` by
+    /// default.
+    ///
+    /// In single-stream mode (see [`Builder::single_stream`]), this is the
+    /// name of the level field in the log line instead.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the name contains invalid
+    /// characters or is already used by a label added with
+    /// [`Builder::label`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .level_label_name("severity")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn level_label_name<S: Into<String>>(mut self, name: S) -> Result<Builder, Error> {
+        self.labels.set_level_name(name.into())?;
+        Ok(self)
+    }
+    /// Set the value of the level label for events of `level`.
+    ///
+    /// The defaults are `"trace"`, `"debug"`, `"info"`, `"warn"` and
+    /// `"error"`. Levels mapped to the same value share a stream. The value
+    /// must be non-empty, at most 2048 bytes long and must not contain
+    /// control characters.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing::Level;
+    ///
+    /// // Syslog severity names.
+    /// let builder = tracing_loki::builder()
+    ///     .level_label_name("severity")?
+    ///     .level_label_value(Level::INFO, "informational")?
+    ///     .level_label_value(Level::WARN, "warning")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn level_label_value<S: Into<String>>(
+        mut self,
+        level: Level,
+        value: S,
+    ) -> Result<Builder, Error> {
+        self.labels.set_level_value(level, value.into())?;
+        Ok(self)
+    }
+    /// Let the string event field `name` override the value of the level
+    /// label.
+    ///
+    /// This allows e.g. marking some `ERROR` events as `critical`. Each
+    /// distinct value results in a separate stream, so the values should
+    /// come from a small set. Events without the field, or with a
+    /// non-string value, use the value for their level.
+    ///
+    /// At most 16 values besides the ones configured for the levels get
+    /// their own stream. Events with further values, or with values that
+    /// aren't valid label values (empty, longer than 2048 bytes or
+    /// containing control characters), also use the value for their level.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .level_label_name("severity")?
+    ///     .level_field("severity");
+    ///
+    /// // Later:
+    /// tracing::error!(severity = "critical", "disk full");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn level_field<S: Into<String>>(mut self, name: S) -> Builder {
+        self.labels.set_level_field(name.into());
+        self
+    }
+    /// Set an extra field that is sent with all log records sent to Loki
+    /// through the built layer.
+    ///
+    /// Fields are meant to be used for open categories or closed categories
+    /// with many options. For example, `"run_id"` with randomly generated
+    /// [UUIDv4](https://en.wikipedia.org/w/index.php?title=Universally_unique_identifier&oldid=1105876960#Version_4_(random))s
+    /// would be a good fit for these extra fields.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .extra_field("run_id", "5b6aedb4-e2c1-4ad9-b8a7-3ef92b5c8120")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extra_field<S: Into<String>, T: Into<String>>(
+        self,
+        key: S,
+        value: T,
+    ) -> Result<Builder, Error> {
+        self.extra_field_value(key, value.into())
+    }
+    /// Set an extra field with an arbitrary JSON value that is sent with all
+    /// log records sent to Loki through the built layer.
+    ///
+    /// Like [`Builder::extra_field`], but the value can also be a number, a
+    /// boolean, an array or an object.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing_loki::serde_json::json;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .extra_field_value("pid", std::process::id())?
+    ///     .extra_field_value("deployment", json!({"region": "eu", "canary": false}))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extra_field_value<S: Into<String>, T: Into<serde_json::Value>>(
+        mut self,
+        key: S,
+        value: T,
+    ) -> Result<Builder, Error> {
+        self.extra_fields.add_static(key.into(), value.into())?;
+        Ok(self)
+    }
+    /// Set an extra field whose value is computed by `f` for each log record
+    /// sent to Loki through the built layer.
+    ///
+    /// `f` is called on the thread emitting the event, so it should be cheap.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::atomic::AtomicU64;
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// static REQUESTS: AtomicU64 = AtomicU64::new(0);
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .extra_field_fn("requests", || REQUESTS.load(Ordering::Relaxed).into())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extra_field_fn<S, F>(mut self, key: S, f: F) -> Result<Builder, Error>
+    where
+        S: Into<String>,
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.extra_fields.add_dynamic(key.into(), Arc::new(f))?;
+        Ok(self)
+    }
+    /// Attach information about the host or process to all log records sent
+    /// to Loki through the built layer, as a label or an extra field.
+    ///
+    /// The resource is detected immediately. See [`Resource`] for the keys
+    /// used.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the resource couldn't be
+    /// detected, or under the same conditions as [`Builder::label`] or
+    /// [`Builder::extra_field`], e.g. when the key is already used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing_loki::Resource;
+    /// use tracing_loki::ResourceTarget;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .resource(Resource::Hostname, ResourceTarget::Label)?
+    ///     .resource(Resource::Pid, ResourceTarget::ExtraField)?
+    ///     .resource(
+    ///         Resource::ExecutableVersion(env!("CARGO_PKG_VERSION")),
+    ///         ResourceTarget::ExtraField,
+    ///     )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn resource(self, resource: Resource, target: ResourceTarget) -> Result<Builder, Error> {
+        let key = resource.key();
+        let value = resource
+            .detect()
+            .ok_or(Error(ErrorI::UndetectedResource(key)))?;
+        match target {
+            ResourceTarget::Label => match value {
+                serde_json::Value::String(value) => self.label(key, value),
+                value => self.label(key, value.to_string()),
+            },
+            ResourceTarget::ExtraField => self.extra_field_value(key, value),
+        }
+    }
+    /// Attach information about the build of the application to all log
+    /// records sent to Loki through the built layer, as labels or extra
+    /// fields.
+    ///
+    /// Use [`build_info!`](crate::build_info!) to capture it. See
+    /// [`BuildInfo`] for the keys used.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error under the same conditions as
+    /// [`Builder::label`] or [`Builder::extra_field`], e.g. when a key is
+    /// already used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing_loki::ResourceTarget;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .build_info(tracing_loki::build_info!(), ResourceTarget::Label)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build_info(mut self, info: BuildInfo, target: ResourceTarget) -> Result<Builder, Error> {
+        for (key, value) in info.entries() {
+            self = match target {
+                ResourceTarget::Label => self.label(key, value)?,
+                ResourceTarget::ExtraField => self.extra_field(key, value)?,
+            };
+        }
+        Ok(self)
+    }
+    /// Attach Kubernetes metadata to all log records sent to Loki through
+    /// the built layer.
+    ///
+    /// The `namespace`, `pod`, `container` and `node` are added as labels,
+    /// and the container ID as the extra field `container_id`, as far as
+    /// they are detected, see [`Kubernetes`]. Outside of Kubernetes, nothing
+    /// is added.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error under the same conditions as
+    /// [`Builder::label`] or [`Builder::extra_field`], e.g. when a key is
+    /// already used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing_loki::Kubernetes;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .kubernetes(Kubernetes::new())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn kubernetes(mut self, detector: Kubernetes) -> Result<Builder, Error> {
+        let metadata = detector.detect();
+        for (key, value) in metadata.labels {
+            self = self.label(key, value)?;
+        }
+        if let Some(container_id) = metadata.container_id {
+            self = self.extra_field("container_id", container_id)?;
+        }
+        Ok(self)
+    }
+    /// Only keep span and event fields whose name matches `pattern`, for
+    /// spans and events whose target starts with `target_prefix`.
+    ///
+    /// `pattern` may contain `*` to match any sequence of characters and `?`
+    /// to match any single character. An empty `target_prefix` applies to
+    /// all targets. Like `tracing_subscriber`'s `EnvFilter` directives, the
+    /// target prefix is matched as a plain string prefix.
+    ///
+    /// Once any allow rule applies to a target, fields of that target that
+    /// are matched by none of them are dropped. Deny rules added with
+    /// [`Builder::deny_fields`] take precedence over allow rules. The
+    /// `message` field of events is always kept, unless a deny rule names it
+    /// exactly.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     // Only keep the `db.*` fields of `sqlx` spans and events.
+    ///     .allow_fields("sqlx", "db.*");
+    /// ```
+    pub fn allow_fields<S: Into<String>, T: Into<String>>(
+        mut self,
+        target_prefix: S,
+        pattern: T,
+    ) -> Builder {
+        self.field_filter
+            .allow(target_prefix.into(), pattern.into(), false);
+        self
+    }
+    /// Like [`Builder::allow_fields`], but only for span fields. Event
+    /// fields of matching targets are kept.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     // Only keep the `http.*` fields of `tower_http` spans.
+    ///     .allow_span_fields("tower_http", "http.*");
+    /// ```
+    pub fn allow_span_fields<S: Into<String>, T: Into<String>>(
+        mut self,
+        target_prefix: S,
+        pattern: T,
+    ) -> Builder {
+        self.field_filter
+            .allow(target_prefix.into(), pattern.into(), true);
+        self
+    }
+    /// Drop span and event fields whose name matches `pattern`, for spans and
+    /// events whose target starts with `target_prefix`.
+    ///
+    /// `pattern` may contain `*` to match any sequence of characters and `?`
+    /// to match any single character. An empty `target_prefix` applies to
+    /// all targets. Like `tracing_subscriber`'s `EnvFilter` directives, the
+    /// target prefix is matched as a plain string prefix.
+    ///
+    /// The `message` field of events is only dropped by a rule whose
+    /// `pattern` is exactly `"message"`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     // Drop all fields of `hyper` spans and events, except for the
+    ///     // message.
+    ///     .deny_fields("hyper", "*")
+    ///     // Never send fields named `password`.
+    ///     .deny_fields("", "password");
+    /// ```
+    pub fn deny_fields<S: Into<String>, T: Into<String>>(
+        mut self,
+        target_prefix: S,
+        pattern: T,
+    ) -> Builder {
+        self.field_filter
+            .deny(target_prefix.into(), pattern.into(), false);
+        self
+    }
+    /// Like [`Builder::deny_fields`], but only for span fields. Event fields
+    /// of matching targets are kept.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     // Drop all span fields of `hyper`.
+    ///     .deny_span_fields("hyper", "*");
+    /// ```
+    pub fn deny_span_fields<S: Into<String>, T: Into<String>>(
+        mut self,
+        target_prefix: S,
+        pattern: T,
+    ) -> Builder {
+        self.field_filter
+            .deny(target_prefix.into(), pattern.into(), true);
+        self
+    }
+    /// Limit the size of the log lines sent to Loki to `max` bytes.
+    ///
+    /// Loki rejects lines longer than its `max_line_size` setting, which fails
+    /// the whole batch they're sent in. Set this to at most that value to
+    /// handle overly long lines before they are sent. What happens to them is
+    /// determined by [`Builder::line_overflow`]. By default, lines aren't
+    /// limited.
+    ///
+    /// A truncated line still contains all keys, non-string values and the
+    /// truncation markers, so very small limits, e.g. below 100 bytes, might
+    /// not be met.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     // Loki's default `max_line_size`.
+    ///     .max_line_size(256 * 1024);
+    /// ```
+    pub fn max_line_size(mut self, max: usize) -> Builder {
+        self.line_limits.max_line_size = Some(max);
+        self
+    }
+    /// Set what to do with log lines longer than [`Builder::max_line_size`].
+    ///
+    /// The default is [`LineOverflow::Truncate`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tracing_loki::LineOverflow;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .max_line_size(256 * 1024)
+    ///     .line_overflow(LineOverflow::Split);
+    /// ```
+    pub fn line_overflow(mut self, overflow: LineOverflow) -> Builder {
+        self.line_limits.overflow = overflow;
+        self
+    }
+    /// Limit the size of individual string span and event field values to
+    /// `max` bytes.
+    ///
+    /// Longer values are cut off and marked with a trailing `…`. By default,
+    /// field values aren't limited.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .max_field_size(4096);
+    /// ```
+    pub fn max_field_size(mut self, max: usize) -> Builder {
+        self.line_limits.max_field_size = Some(max);
+        self
+    }
+    /// Limit the number of events sent from each callsite to `per_second`
+    /// on average, allowing bursts of up to `burst` events.
+    ///
+    /// Events exceeding the limit are dropped. While that happens, an event
+    /// `"suppressed N events from <callsite>"` is sent periodically, see
+    /// [`Builder::suppressed_summary_interval`]. By default, events aren't
+    /// rate limited.
+    ///
+    /// This overrides limits previously set by
+    /// [`Builder::level_rate_limit`].
+    ///
+    /// Returns an error unless `per_second` is a positive, finite number and
+    /// `burst` is at least 1, since no event would be sent otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .rate_limit(10.0, 100)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> Result<Builder, Error> {
+        self.rate_limits.set_bucket(
+            None,
+            TokenBucket {
+                per_second,
+                burst: burst.into(),
+            },
+        )?;
+        Ok(self)
+    }
+    /// Limit the number of events of the given level sent from each callsite
+    /// to `per_second` on average, allowing bursts of up to `burst` events.
+    ///
+    /// See [`Builder::rate_limit`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing::Level;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .level_rate_limit(Level::WARN, 1.0, 10)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn level_rate_limit(
+        mut self,
+        level: Level,
+        per_second: f64,
+        burst: u32,
+    ) -> Result<Builder, Error> {
+        self.rate_limits.set_bucket(
+            Some(level),
+            TokenBucket {
+                per_second,
+                burst: burst.into(),
+            },
+        )?;
+        Ok(self)
+    }
+    /// Only send a random fraction `ratio` (between `0.0` and `1.0`) of the
+    /// events.
+    ///
+    /// Sampling happens before rate limiting, events dropped by sampling
+    /// aren't counted as suppressed. By default, all events are sent.
+    ///
+    /// This overrides ratios previously set by
+    /// [`Builder::level_sample_ratio`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .sample_ratio(0.5);
+    /// ```
+    pub fn sample_ratio(mut self, ratio: f64) -> Builder {
+        self.rate_limits.set_sample_ratio(None, ratio);
+        self
+    }
+    /// Only send a random fraction `ratio` (between `0.0` and `1.0`) of the
+    /// events of the given level.
+    ///
+    /// See [`Builder::sample_ratio`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tracing::Level;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .level_sample_ratio(Level::DEBUG, 0.01);
+    /// ```
+    pub fn level_sample_ratio(mut self, level: Level, ratio: f64) -> Builder {
+        self.rate_limits.set_sample_ratio(Some(level), ratio);
+        self
+    }
+    /// Set how often to report events suppressed by rate limiting.
+    ///
+    /// The suppressed events of a callsite are reported once it's allowed to
+    /// send events again, and at most this often while it keeps exceeding its
+    /// limit. If it stops logging, the last suppressed events are reported
+    /// about this long after the previous report. The default is 10 seconds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .rate_limit(10.0, 100)?
+    ///     .suppressed_summary_interval(Duration::from_secs(60));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn suppressed_summary_interval(mut self, interval: Duration) -> Builder {
+        self.rate_limits.set_summary_interval(interval);
+        self
+    }
+    /// Take the timestamp of log records from the event field `name` instead
+    /// of the time the event was emitted.
+    ///
+    /// This is useful when replaying or forwarding logs that already carry
+    /// their original time. The field's value is interpreted according to
+    /// `format`; if the field is missing or its value can't be parsed, the
+    /// time the event was emitted is used. The field is still sent as part of
+    /// the log line.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tracing_loki::TimestampFormat;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .timestamp_field("recorded_at", TimestampFormat::Rfc3339);
+    ///
+    /// // Later:
+    /// tracing::info!(recorded_at = "2023-08-01T12:34:56Z", "replayed job");
+    /// ```
+    pub fn timestamp_field<S: Into<String>>(mut self, name: S, format: TimestampFormat) -> Builder {
+        self.timestamp_field = Some((name.into(), format));
+        self
+    }
+    /// Coalesce consecutive identical log lines of the same stream within
+    /// `window`.
+    ///
+    /// The first line is sent as usual. Identical lines following it are
+    /// counted instead and sent as a single line with the additional fields
+    /// `repeat_count`, `_first_timestamp` and `_last_timestamp` once a
+    /// different line is logged or `window` has passed since the first
+    /// repetition. By default, all lines are sent.
+    ///
+    /// # Example
     ///
-    /// For open categories, extra fields are a better fit. See
-    /// [`Builder::extra_field`].
+    /// ```
+    /// use std::time::Duration;
     ///
-    /// No two labels can share the same name, and the key `"level"` is
-    /// reserved for the log level.
+    /// let builder = tracing_loki::builder()
+    ///     .dedup_window(Duration::from_secs(10));
+    /// ```
+    pub fn dedup_window(mut self, window: Duration) -> Builder {
+        self.task_options.dedup_window = Some(window);
+        self
+    }
+    /// Make the timestamps of each stream strictly increasing.
     ///
-    /// # Errors
+    /// Events are timestamped on the thread emitting them, so they can be
+    /// queued out of order or with identical timestamps, which Loki rejects
+    /// unless it is configured to accept out-of-order writes. With this
+    /// option, each batch is sorted by timestamp before sending, and
+    /// timestamps not after the previous entry of the stream are moved
+    /// forward to one nanosecond past it. The number of adjusted timestamps
+    /// is reported as a `DEBUG` event and counted by
+    /// [`BackgroundTaskController::adjusted_timestamps`]. By default,
+    /// timestamps are sent as is.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .monotonic_timestamps(true);
+    /// ```
+    pub fn monotonic_timestamps(mut self, enabled: bool) -> Builder {
+        self.task_options.monotonic_timestamps = enabled;
+        self
+    }
+    /// Send all levels in a single stream, carrying the level only as the
+    /// `level` field of the log line, or the field named with
+    /// [`Builder::level_label_name`].
+    ///
+    /// By default, the level is a label, so each set of labels results in
+    /// five streams in Loki, one per level. In single-stream mode, there's
+    /// only one stream, reducing the number of streams and preserving the
+    /// order of log lines across levels. The level can still be queried with
+    /// e.g. `{host="mine"} | json | level="error"`.
     ///
-    /// This function will return an error if a key is a duplicate or when the
-    /// key is `"level"`.
+    /// Loki requires at least one label per stream, so building fails if no
+    /// label was added with [`Builder::label`].
     ///
     /// # Example
     ///
@@ -61,50 +847,109 @@ impl Builder {
     /// # use tracing_loki::Error;
     /// # fn main() -> Result<(), Error> {
     /// let builder = tracing_loki::builder()
-    ///     .label("environment", "production")?;
+    ///     .label("host", "mine")?
+    ///     .single_stream(true);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn label<S: Into<String>, T: AsRef<str>>(
-        mut self,
-        key: S,
-        value: T,
-    ) -> Result<Builder, Error> {
-        self.labels.add(key.into(), value.as_ref())?;
-        Ok(self)
+    pub fn single_stream(mut self, enabled: bool) -> Builder {
+        self.task_options.single_stream = enabled;
+        self
     }
-    /// Set an extra field that is sent with all log records sent to Loki
-    /// through the built layer.
+    /// Set the clock used for timestamping events and for the timers of the
+    /// background task, like the backoff between retries.
     ///
-    /// Fields are meant to be used for open categories or closed categories
-    /// with many options. For example, `"run_id"` with randomly generated
-    /// [UUIDv4](https://en.wikipedia.org/w/index.php?title=Universally_unique_identifier&oldid=1105876960#Version_4_(random))s
-    /// would be a good fit for these extra fields.
+    /// The default is [`SystemClock`](crate::SystemClock). Supplying a fake
+    /// clock makes the shipped timestamps and the retry schedule
+    /// deterministic, which is mostly useful in tests.
     ///
     /// # Example
     ///
     /// ```
-    /// # use tracing_loki::Error;
-    /// # fn main() -> Result<(), Error> {
+    /// use std::future::Future;
+    /// use std::pin::Pin;
+    /// use std::time::Duration;
+    /// use std::time::SystemTime;
+    /// use tracing_loki::Clock;
+    ///
+    /// struct Epoch;
+    ///
+    /// impl Clock for Epoch {
+    ///     fn now(&self) -> SystemTime {
+    ///         SystemTime::UNIX_EPOCH
+    ///     }
+    ///     fn sleep(&self, _: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    ///         Box::pin(std::future::ready(()))
+    ///     }
+    /// }
+    ///
     /// let builder = tracing_loki::builder()
-    ///     .extra_field("run_id", "5b6aedb4-e2c1-4ad9-b8a7-3ef92b5c8120")?;
-    /// # Ok(())
-    /// # }
+    ///     .clock(Epoch);
     /// ```
-    pub fn extra_field<S: Into<String>, T: Into<String>>(
-        mut self,
-        key: S,
-        value: T,
-    ) -> Result<Builder, Error> {
-        match self.extra_fields.entry(key.into()) {
-            hash_map::Entry::Occupied(o) => {
-                return Err(Error(ErrorI::DuplicateExtraField(o.key().clone())));
-            }
-            hash_map::Entry::Vacant(v) => {
-                v.insert(value.into());
-            }
-        }
-        Ok(self)
+    pub fn clock<C: Clock>(mut self, clock: C) -> Builder {
+        self.task_options.clock = Arc::new(clock);
+        self
+    }
+    /// Set the maximum number of push requests to Loki that can be in flight
+    /// at the same time.
+    ///
+    /// Loki requires the entries of each stream to arrive in order, so each
+    /// stream (a combination of labels, including the level) only ever has
+    /// one request in flight. Additional requests can be sent for the other
+    /// streams while waiting for the response. This helps throughput when
+    /// the round trip to Loki is slow. The default is 1.
+    ///
+    /// The maximum is therefore capped at the number of streams: one per
+    /// level, plus those created by [`Builder::level_field`], or one with
+    /// [`Builder::single_stream`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .max_concurrent_requests(4);
+    /// ```
+    pub fn max_concurrent_requests(mut self, max: usize) -> Builder {
+        self.task_options.max_concurrent_requests = max;
+        self
+    }
+    /// Set the backoff between retries of failed push requests.
+    ///
+    /// After the first failure, the request is retried immediately. The
+    /// backoff then starts at `initial` and doubles with each consecutive
+    /// failure, up to `max`. The defaults are 500 milliseconds and 10
+    /// minutes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .retry_backoff(Duration::from_secs(1), Duration::from_secs(60));
+    /// ```
+    pub fn retry_backoff(mut self, initial: Duration, max: Duration) -> Builder {
+        self.task_options.retry.initial_backoff = initial;
+        self.task_options.retry.max_backoff = max;
+        self
+    }
+    /// Drop the entries of a failing request once the backoff between
+    /// retries reaches `threshold`, see [`Builder::retry_backoff`].
+    ///
+    /// This keeps memory bounded while Loki is unreachable. Entries logged
+    /// afterwards are still queued and retried. The default is 30 seconds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .drop_after_backoff(Duration::from_secs(300));
+    /// ```
+    pub fn drop_after_backoff(mut self, threshold: Duration) -> Builder {
+        self.task_options.retry.drop_after = threshold;
+        self
     }
     /// Set an extra HTTP header to be sent with all requests sent to Loki.
     ///
@@ -143,6 +988,407 @@ impl Builder {
         }
         Ok(self)
     }
+    /// Fail each push request that takes longer than `timeout`, including
+    /// redirects, the retry after a `401 Unauthorized` response and the time
+    /// to obtain a bearer or OAuth2 access token.
+    ///
+    /// Timed out requests are reported and retried after a backoff like
+    /// other failed requests, see [`Builder::retry_backoff`]. By default,
+    /// requests don't time out, so a hung connection to Loki stalls sending.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .request_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn request_timeout(mut self, timeout: Duration) -> Builder {
+        self.task_options.request_timeout = Some(timeout);
+        self
+    }
+    /// Fail connection attempts to Loki that take longer than `timeout`.
+    ///
+    /// By default, the operating system's timeout applies. Like the other
+    /// connection settings, this can't be combined with a client supplied
+    /// with [`Builder::http_client`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .connect_timeout(Duration::from_secs(5));
+    /// ```
+    pub fn connect_timeout(mut self, timeout: Duration) -> Builder {
+        self.task_options.connection.connect_timeout = Some(timeout);
+        self
+    }
+    /// Close connections to Loki that have been idle for `timeout`. The
+    /// default is 90 seconds.
+    ///
+    /// Set this below the idle timeout of load balancers in front of Loki,
+    /// so that requests aren't sent on connections they already closed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .pool_idle_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.task_options.connection.pool_idle_timeout = Some(timeout);
+        self
+    }
+    /// Send TCP keepalive probes on connections to Loki after they've been
+    /// idle for `interval`. By default, no probes are sent.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .tcp_keepalive(Duration::from_secs(60));
+    /// ```
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Builder {
+        self.task_options.connection.tcp_keepalive = Some(interval);
+        self
+    }
+    /// Talk HTTP/2 to Loki right away, without negotiating it.
+    ///
+    /// This is needed for HTTP/2 over unencrypted connections, e.g. to a
+    /// gRPC-capable proxy in front of Loki. By default, HTTP/1.1 is used
+    /// unless HTTP/2 is negotiated during the TLS handshake.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .http2_prior_knowledge(true);
+    /// ```
+    pub fn http2_prior_knowledge(mut self, enabled: bool) -> Builder {
+        self.task_options.connection.http2_prior_knowledge = enabled;
+        self
+    }
+    /// Send requests to Loki with `client` instead of a client created by
+    /// this crate, e.g. to share its connection pool, proxy settings and
+    /// timeouts.
+    ///
+    /// The user agent, the headers set with [`Builder::http_header`] and the
+    /// credentials are still added to each request. Redirects are handled
+    /// per request, so `client` should be built with
+    /// [`redirect::Policy::none()`](reqwest::redirect::Policy::none).
+    /// Redirects followed by `client` itself fail the request, since they
+    /// can silently drop the request body. Such requests aren't retried, to
+    /// avoid sending the entries twice, their entries are dropped instead.
+    ///
+    /// The TLS and connection options of this builder don't apply to
+    /// `client`, building the layer fails if any of them are set.
+    /// [`Builder::request_timeout`] does apply.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use tracing_loki::reqwest;
+    ///
+    /// let client = reqwest::Client::builder()
+    ///     .redirect(reqwest::redirect::Policy::none())
+    ///     .timeout(Duration::from_secs(10))
+    ///     .build()
+    ///     .unwrap();
+    /// let builder = tracing_loki::builder()
+    ///     .http_client(client);
+    /// ```
+    pub fn http_client(mut self, client: reqwest::Client) -> Builder {
+        self.task_options.http_client = Some(client);
+        self
+    }
+    /// Authenticate to Loki using HTTP basic authentication.
+    ///
+    /// For Grafana Cloud, the username is the numeric user ID of the Loki
+    /// instance and the password is an access policy token.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .basic_auth("123456", "glc_secret");
+    /// ```
+    pub fn basic_auth<S: Into<String>, T: Into<String>>(
+        mut self,
+        username: S,
+        password: T,
+    ) -> Builder {
+        self.task_options.auth = Some(Arc::new(Auth::basic(username.into(), password.into())));
+        self
+    }
+    /// Authenticate to Loki with a fixed bearer token.
+    ///
+    /// Use [`Builder::bearer_token_fn`] or [`Builder::bearer_token_file`]
+    /// for tokens that expire.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// let builder = tracing_loki::builder()
+    ///     .bearer_token("secret")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bearer_token<S: Into<String>>(mut self, token: S) -> Result<Builder, Error> {
+        self.task_options.auth = Some(Arc::new(Auth::bearer_static(token.into())?));
+        Ok(self)
+    }
+    /// Authenticate to Loki with a bearer token returned by `f`.
+    ///
+    /// `f` is called before the first request. The token is reused until it
+    /// is about to expire, if `f` returned a [`BearerToken`] with an expiry
+    /// time, or until Loki rejects a request with `401 Unauthorized`. In the
+    /// latter case, `f` is called again and the request is retried once
+    /// with the new token. If `f` fails, the request fails and is retried
+    /// after a backoff, see [`Builder::retry_backoff`].
+    ///
+    /// `f` runs on Tokio's blocking thread pool, so it may block, e.g. to
+    /// run a command that prints a token.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use std::time::SystemTime;
+    /// use tracing_loki::BearerToken;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .bearer_token_fn(|| std::env::var("LOKI_TOKEN"));
+    ///
+    /// // Tokens that expire after an hour.
+    /// let builder = tracing_loki::builder()
+    ///     .bearer_token_fn(|| {
+    ///         let token = std::env::var("LOKI_TOKEN")?;
+    ///         let expires_at = SystemTime::now() + Duration::from_secs(3600);
+    ///         Ok::<_, std::env::VarError>(BearerToken::new(token).expires_at(expires_at))
+    ///     });
+    /// ```
+    pub fn bearer_token_fn<F, T, E>(mut self, f: F) -> Builder
+    where
+        F: Fn() -> Result<T, E> + Send + Sync + 'static,
+        T: Into<BearerToken>,
+        E: Into<TokenError>,
+    {
+        let f = move || f().map(Into::into).map_err(Into::into);
+        self.task_options.auth = Some(Arc::new(Auth::bearer_fn(f)));
+        self
+    }
+    /// Authenticate to Loki with a bearer token read from the file at
+    /// `path`, ignoring surrounding whitespace.
+    ///
+    /// The file is read again when its modification time changes, e.g. when
+    /// a rotated Kubernetes service account token is written, and when Loki
+    /// rejects a request with `401 Unauthorized`, in which case the request
+    /// is retried once with the new token.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .bearer_token_file("/var/run/secrets/tokens/loki");
+    /// ```
+    pub fn bearer_token_file<P: Into<PathBuf>>(mut self, path: P) -> Builder {
+        self.task_options.auth = Some(Arc::new(Auth::bearer_file(path.into())));
+        self
+    }
+    /// Authenticate to Loki with access tokens obtained using the OAuth2
+    /// client credentials grant.
+    ///
+    /// The background task requests a token before the first push request
+    /// and reuses it until shortly before it expires, according to the
+    /// `expires_in` of the token response. When Loki rejects a request with
+    /// `401 Unauthorized`, a new token is requested and the request is
+    /// retried once. Failures to obtain a token are reported like failed
+    /// push requests and retried after a backoff, see
+    /// [`Builder::retry_backoff`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tracing_loki::OAuth2;
+    /// use url::Url;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .oauth2(OAuth2::client_credentials(
+    ///         Url::parse("https://auth.example.com/oauth2/token").unwrap(),
+    ///         "loki-shipper",
+    ///         "secret",
+    ///     ));
+    /// ```
+    pub fn oauth2(mut self, credentials: OAuth2) -> Builder {
+        self.task_options.auth = Some(Arc::new(Auth::oauth2(credentials)));
+        self
+    }
+    /// Trust the certificates in `pem` when connecting to Loki, in addition
+    /// to the built-in root certificates.
+    ///
+    /// `pem` can contain several certificates, e.g. a CA bundle. This is
+    /// needed when Loki's certificate is signed by a private CA. Can be
+    /// called multiple times.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `pem` contains no certificate,
+    /// or, with the `native-tls` feature, an invalid one. With the `rustls`
+    /// feature, invalid certificates are reported when building.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let pem = std::fs::read("/etc/ssl/private-ca.pem")?;
+    /// let builder = tracing_loki::builder()
+    ///     .tls_root_certificates_pem(&pem)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn tls_root_certificates_pem<P: AsRef<[u8]>>(mut self, pem: P) -> Result<Builder, Error> {
+        self.task_options.tls.add_root_certificates(pem.as_ref())?;
+        Ok(self)
+    }
+    /// Present a client certificate to Loki, for mutual TLS.
+    ///
+    /// `pem` must contain the private key, in RSA or PKCS#8 format, and the
+    /// certificate chain. This requires the `rustls` feature. If the
+    /// `native-tls` feature is enabled as well, the connection uses rustls.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut pem = std::fs::read("client.key")?;
+    /// pem.extend(std::fs::read("client.crt")?);
+    /// let builder = tracing_loki::builder()
+    ///     .tls_client_identity_pem(&pem)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "rustls")]
+    pub fn tls_client_identity_pem<P: AsRef<[u8]>>(mut self, pem: P) -> Result<Builder, Error> {
+        self.task_options.tls.set_identity_pem(pem.as_ref())?;
+        Ok(self)
+    }
+    /// Present a client certificate to Loki, for mutual TLS.
+    ///
+    /// `der` is a PKCS #12 archive containing the private key and the
+    /// certificate chain, encrypted with `password`, as created by e.g.
+    /// `openssl pkcs12 -export`. This requires the `native-tls` feature.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let der = std::fs::read("client.p12")?;
+    /// let builder = tracing_loki::builder()
+    ///     .tls_client_identity_pkcs12(&der, "password")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "native-tls")]
+    pub fn tls_client_identity_pkcs12<P: AsRef<[u8]>>(
+        mut self,
+        der: P,
+        password: &str,
+    ) -> Result<Builder, Error> {
+        self.task_options
+            .tls
+            .set_identity_pkcs12(der.as_ref(), password)?;
+        Ok(self)
+    }
+    /// Set the minimum TLS version accepted when connecting to Loki.
+    ///
+    /// [`TlsVersion::Tls1_3`](crate::TlsVersion::Tls1_3) is only supported
+    /// with the `rustls` feature. Building fails otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tracing_loki::TlsVersion;
+    ///
+    /// let builder = tracing_loki::builder()
+    ///     .tls_min_version(TlsVersion::Tls1_2);
+    /// ```
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn tls_min_version(mut self, version: TlsVersion) -> Builder {
+        self.task_options.tls.set_min_version(version);
+        self
+    }
+    /// Accept any certificate presented by Loki, including expired and
+    /// self-signed ones and those for a different hostname.
+    ///
+    /// **This makes the connection vulnerable to man-in-the-middle attacks.**
+    /// Only use it for local development. Prefer
+    /// [`Builder::tls_root_certificates_pem`] for self-signed certificates.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = tracing_loki::builder()
+    ///     .danger_accept_invalid_certs(cfg!(debug_assertions));
+    /// ```
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Builder {
+        self.task_options.tls.set_accept_invalid_certs(accept);
+        self
+    }
+    /// Build the tracing [`Layer`] and its corresponding [`BackgroundTask`],
+    /// sending to the URL set with [`Builder::loki_url`] or from the
+    /// environment.
+    ///
+    /// Like [`Builder::build_url`], this function **does not strip off** the
+    /// path component of the URL.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no URL was set, in addition to
+    /// the errors of [`Builder::build_url`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Error> {
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::util::SubscriberInitExt;
+    ///
+    /// # std::env::set_var("LOKI_URL", "http://127.0.0.1:3100");
+    /// let (layer, task) = tracing_loki::Builder::from_env()?.build()?;
+    /// tracing_subscriber::registry().with(layer).init();
+    /// tokio::spawn(task);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build(mut self) -> Result<(Layer, BackgroundTask), Error> {
+        let loki_url = self.loki_url.take().ok_or(Error(ErrorI::MissingLokiUrl))?;
+        self.build_url(loki_url)
+    }
+    /// Build the tracing [`Layer`], [`BackgroundTask`] and its
+    /// [`BackgroundTaskController`], sending to the URL set with
+    /// [`Builder::loki_url`] or from the environment.
+    ///
+    /// See [`Builder::build`] and [`Builder::build_controller_url`].
+    pub fn build_controller(
+        mut self,
+    ) -> Result<(Layer, BackgroundTaskController, BackgroundTask), Error> {
+        let loki_url = self.loki_url.take().ok_or(Error(ErrorI::MissingLokiUrl))?;
+        self.build_controller_url(loki_url)
+    }
     /// Build the tracing [`Layer`] and its corresponding [`BackgroundTask`].
     ///
     /// The `loki_url` is the URL of the Loki server, like
@@ -159,12 +1405,31 @@ impl Builder {
     /// See the crate's root documentation for an example.
     pub fn build_url(self, loki_url: Url) -> Result<(Layer, BackgroundTask), Error> {
         let (sender, receiver) = event_channel();
+        let rate_limiter = Arc::new(RateLimiter::new(self.rate_limits));
         Ok((
             Layer {
                 sender,
-                extra_fields: self.extra_fields,
+                extra_fields: self.extra_fields.clone(),
+                field_filter: self.field_filter,
+                max_field_size: self.line_limits.max_field_size,
+                rate_limiter: rate_limiter.clone(),
+                clock: self.task_options.clock.clone(),
             },
-            BackgroundTask::new(loki_url, self.http_headers, receiver, &self.labels)?,
+            BackgroundTask::new(
+                loki_url,
+                self.http_headers,
+                receiver,
+                EventFormatter {
+                    extra_fields: self.extra_fields,
+                    line_limits: self.line_limits,
+                    timestamp_field: self.timestamp_field,
+                    level_label: self.labels.level_label().clone(),
+                    level_in_line: self.task_options.single_stream,
+                },
+                &self.labels,
+                rate_limiter,
+                self.task_options,
+            )?,
         ))
     }
     /// Build the tracing [`Layer`], [`BackgroundTask`] and its
@@ -189,14 +1454,11 @@ impl Builder {
         self,
         loki_url: Url,
     ) -> Result<(Layer, BackgroundTaskController, BackgroundTask), Error> {
-        let (sender, receiver) = event_channel();
-        Ok((
-            Layer {
-                sender: sender.clone(),
-                extra_fields: self.extra_fields,
-            },
-            BackgroundTaskController { sender },
-            BackgroundTask::new(loki_url, self.http_headers, receiver, &self.labels)?,
-        ))
+        let (layer, task) = self.build_url(loki_url)?;
+        let controller = BackgroundTaskController {
+            sender: layer.sender.clone(),
+            adjusted_timestamps: task.adjusted_timestamps.clone(),
+        };
+        Ok((layer, controller, task))
     }
 }
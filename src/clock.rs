@@ -0,0 +1,112 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Source of the current time and of timers.
+///
+/// The [`Layer`](crate::Layer) uses it to timestamp events, and the
+/// [`BackgroundTask`](crate::BackgroundTask) uses it for its timers, like the
+/// backoff between retries. The default is [`SystemClock`]. A custom clock
+/// can be set with [`Builder::clock`](crate::Builder::clock), e.g. to get
+/// deterministic timestamps and retry schedules in tests.
+pub trait Clock: Send + Sync + 'static {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+    /// A future that completes after `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The real clock, using [`SystemTime::now`] and [`tokio::time::sleep`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::Clock;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+    use tracing_subscriber::layer::SubscriberExt;
+    use url::Url;
+
+    /// A clock standing still at 1000 seconds after the epoch unless
+    /// advanced, with timers that complete immediately and are recorded.
+    #[derive(Clone)]
+    pub struct FakeClock {
+        pub now: Arc<Mutex<SystemTime>>,
+        pub sleeps: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl Default for FakeClock {
+        fn default() -> FakeClock {
+            FakeClock {
+                now: Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1000))),
+                sleeps: Default::default(),
+            }
+        }
+    }
+
+    impl FakeClock {
+        pub fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap()
+        }
+        fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            self.sleeps.lock().unwrap().push(duration);
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    #[test]
+    fn timestamp() {
+        let (layer, mut task) = crate::builder()
+            .clock(FakeClock::default())
+            .build_url(Url::parse("http://127.0.0.1:3100").unwrap())
+            .unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || tracing::info!("hello"));
+        let event = task.receiver.try_recv().unwrap().unwrap();
+        assert_eq!(event.timestamp, UNIX_EPOCH + Duration::from_secs(1000));
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        let clock = FakeClock::default();
+        // Nothing should be listening on port 1.
+        let (layer, task) = crate::builder()
+            .clock(clock.clone())
+            .build_url(Url::parse("http://127.0.0.1:1").unwrap())
+            .unwrap();
+        // Keep the layer alive so that the background task doesn't quit.
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || tracing::info!(target: "app", "hello"));
+        let task = tokio::spawn(task);
+        while clock.sleeps.lock().unwrap().len() < 4 {
+            tokio::task::yield_now().await;
+        }
+        task.abort();
+        assert_eq!(
+            clock.sleeps.lock().unwrap()[..4],
+            [0, 500, 1000, 2000].map(Duration::from_millis),
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use std::fs;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use std::path::Path;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing_core::Level;
+use url::Url;
+
+use super::builder;
+use super::Builder;
+use super::Error;
+use super::ErrorI;
+use super::LineOverflow;
+use super::TaskOptions;
+use super::TimestampFormat;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use super::TlsVersion;
+
+/// Configuration of a [`Builder`] that can be deserialized from a config
+/// file, see [`Config::into_builder`].
+///
+/// All fields are optional and unknown fields are rejected. Durations are
+/// given in milliseconds. In TOML, a configuration could look like this:
+///
+/// ```toml
+/// url = "http://127.0.0.1:3100"
+/// tenant = "team-a"
+///
+/// [labels]
+/// host = "mine"
+///
+/// [extra_fields]
+/// pid = 4242
+///
+/// [headers]
+/// X-Custom = "value"
+///
+/// [batching]
+/// max_concurrent_requests = 4
+/// dedup_window_ms = 10000
+///
+/// [retry]
+/// initial_backoff_ms = 1000
+/// max_backoff_ms = 60000
+///
+/// [http]
+/// connect_timeout_ms = 5000
+/// request_timeout_ms = 30000
+///
+/// [format]
+/// level_label = "severity"
+/// max_line_size = 262144
+/// line_overflow = "split"
+///
+/// [tls]
+/// ca_file = "/etc/ssl/private-ca.pem"
+/// min_version = "1.2"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// The URL of the Loki server, see [`Builder::loki_url`].
+    pub url: Option<String>,
+    /// See [`Builder::label`].
+    pub labels: BTreeMap<String, String>,
+    /// See [`Builder::sanitize_labels`].
+    pub sanitize_labels: bool,
+    /// See [`Builder::extra_field_value`].
+    pub extra_fields: BTreeMap<String, serde_json::Value>,
+    /// See [`Builder::http_header`].
+    pub headers: BTreeMap<String, String>,
+    /// The tenant ID, sent as the `X-Scope-OrgID` header.
+    pub tenant: Option<String>,
+    /// Options for sending the log records.
+    pub batching: BatchingConfig,
+    /// Options for retrying failed requests.
+    pub retry: RetryConfig,
+    /// Options for the HTTP connections to Loki.
+    pub http: HttpConfig,
+    /// Options for formatting the log records.
+    pub format: FormatConfig,
+    /// Options for TLS connections to Loki.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub tls: TlsConfig,
+}
+
+/// The `batching` section of a [`Config`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct BatchingConfig {
+    /// See [`Builder::max_concurrent_requests`].
+    pub max_concurrent_requests: Option<usize>,
+    /// See [`Builder::dedup_window`].
+    pub dedup_window_ms: Option<u64>,
+    /// See [`Builder::monotonic_timestamps`].
+    pub monotonic_timestamps: bool,
+    /// See [`Builder::single_stream`].
+    pub single_stream: bool,
+}
+
+/// The `retry` section of a [`Config`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct RetryConfig {
+    /// See [`Builder::retry_backoff`].
+    pub initial_backoff_ms: Option<u64>,
+    /// See [`Builder::retry_backoff`].
+    pub max_backoff_ms: Option<u64>,
+    /// See [`Builder::drop_after_backoff`].
+    pub drop_after_ms: Option<u64>,
+}
+
+/// The `http` section of a [`Config`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct HttpConfig {
+    /// See [`Builder::connect_timeout`].
+    pub connect_timeout_ms: Option<u64>,
+    /// See [`Builder::request_timeout`].
+    pub request_timeout_ms: Option<u64>,
+    /// See [`Builder::pool_idle_timeout`].
+    pub pool_idle_timeout_ms: Option<u64>,
+    /// See [`Builder::tcp_keepalive`].
+    pub tcp_keepalive_ms: Option<u64>,
+    /// See [`Builder::http2_prior_knowledge`].
+    pub http2_prior_knowledge: bool,
+}
+
+/// The `format` section of a [`Config`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct FormatConfig {
+    /// See [`Builder::level_label_name`].
+    pub level_label: Option<String>,
+    /// The values of the level label by level name, see
+    /// [`Builder::level_label_value`].
+    pub level_values: BTreeMap<String, String>,
+    /// See [`Builder::level_field`].
+    pub level_field: Option<String>,
+    /// See [`Builder::max_line_size`].
+    pub max_line_size: Option<usize>,
+    /// See [`Builder::line_overflow`].
+    pub line_overflow: Option<LineOverflow>,
+    /// See [`Builder::max_field_size`].
+    pub max_field_size: Option<usize>,
+    /// See [`Builder::timestamp_field`].
+    pub timestamp_field: Option<TimestampFieldConfig>,
+}
+
+/// The `tls` section of a [`Config`].
+///
+/// Files are read by [`Config::into_builder`].
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct TlsConfig {
+    /// A PEM file with certificates to trust, see
+    /// [`Builder::tls_root_certificates_pem`].
+    pub ca_file: Option<PathBuf>,
+    /// A PEM file with the client's private key and certificate chain, see
+    /// [`Builder::tls_client_identity_pem`].
+    #[cfg(feature = "rustls")]
+    pub client_identity_pem_file: Option<PathBuf>,
+    /// A PKCS #12 file with the client's private key and certificate chain,
+    /// see [`Builder::tls_client_identity_pkcs12`].
+    #[cfg(feature = "native-tls")]
+    pub client_identity_pkcs12_file: Option<PathBuf>,
+    /// The password of `client_identity_pkcs12_file`.
+    #[cfg(feature = "native-tls")]
+    pub client_identity_password: String,
+    /// See [`Builder::tls_min_version`].
+    pub min_version: Option<TlsVersion>,
+    /// See [`Builder::danger_accept_invalid_certs`].
+    pub danger_accept_invalid_certs: bool,
+}
+
+/// The `format.timestamp_field` section of a [`Config`], see
+/// [`Builder::timestamp_field`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct TimestampFieldConfig {
+    /// The name of the field.
+    pub name: String,
+    /// How the value of the field is interpreted.
+    pub format: TimestampFormat,
+}
+
+fn at(path: String, error: ErrorI) -> Error {
+    Error(ErrorI::Config(path, Box::new(error)))
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn read(path: &str, file: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(file).map_err(|e| {
+        let file = file.display().to_string();
+        at(path.into(), ErrorI::ReadFile(file, e.to_string()))
+    })
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn apply_tls(mut builder: Builder, tls: TlsConfig) -> Result<Builder, Error> {
+    if let Some(file) = tls.ca_file {
+        let path = "tls.ca_file";
+        builder = builder
+            .tls_root_certificates_pem(read(path, &file)?)
+            .map_err(|e| at(path.into(), e.0))?;
+    }
+    #[cfg(feature = "rustls")]
+    if let Some(file) = tls.client_identity_pem_file {
+        let path = "tls.client_identity_pem_file";
+        builder = builder
+            .tls_client_identity_pem(read(path, &file)?)
+            .map_err(|e| at(path.into(), e.0))?;
+    }
+    #[cfg(feature = "native-tls")]
+    if let Some(file) = tls.client_identity_pkcs12_file {
+        let path = "tls.client_identity_pkcs12_file";
+        builder = builder
+            .tls_client_identity_pkcs12(read(path, &file)?, &tls.client_identity_password)
+            .map_err(|e| at(path.into(), e.0))?;
+    }
+    if let Some(version) = tls.min_version {
+        builder = builder.tls_min_version(version);
+    }
+    Ok(builder.danger_accept_invalid_certs(tls.danger_accept_invalid_certs))
+}
+
+impl Config {
+    /// Create a [`Builder`] from this configuration.
+    ///
+    /// Errors name the offending entry, e.g. `config labels.2k: invalid
+    /// label character '2' in key "2k"`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use tracing_loki::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// use tracing_loki::serde_json::json;
+    /// use tracing_loki::Config;
+    ///
+    /// // Typically read from a file with a format crate like `toml`.
+    /// let config: Config = tracing_loki::serde_json::from_value(json!({
+    ///     "url": "http://127.0.0.1:3100",
+    ///     "labels": {"host": "mine"},
+    ///     "retry": {"max_backoff_ms": 60000},
+    /// }))
+    /// .unwrap();
+    /// let (layer, task) = config.into_builder()?.build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_builder(self) -> Result<Builder, Error> {
+        let mut builder = builder().sanitize_labels(self.sanitize_labels);
+        if let Some(url) = self.url {
+            let url =
+                Url::parse(url.trim()).map_err(|_| at("url".into(), ErrorI::InvalidLokiUrl))?;
+            builder = builder.loki_url(url);
+        }
+
+        let format = self.format;
+        if let Some(name) = format.level_label {
+            builder = builder
+                .level_label_name(name)
+                .map_err(|e| at("format.level_label".into(), e.0))?;
+        }
+        for (level, value) in format.level_values {
+            let path = format!("format.level_values.{}", level);
+            let level = level
+                .parse::<Level>()
+                .map_err(|_| at(path.clone(), ErrorI::UnknownLevel(level)))?;
+            builder = builder
+                .level_label_value(level, value)
+                .map_err(|e| at(path, e.0))?;
+        }
+        if let Some(field) = format.level_field {
+            builder = builder.level_field(field);
+        }
+        if let Some(max) = format.max_line_size {
+            builder = builder.max_line_size(max);
+        }
+        if let Some(overflow) = format.line_overflow {
+            builder = builder.line_overflow(overflow);
+        }
+        if let Some(max) = format.max_field_size {
+            builder = builder.max_field_size(max);
+        }
+        if let Some(field) = format.timestamp_field {
+            builder = builder.timestamp_field(field.name, field.format);
+        }
+
+        for (key, value) in self.labels {
+            let path = format!("labels.{}", key);
+            builder = builder.label(key, value).map_err(|e| at(path, e.0))?;
+        }
+        for (key, value) in self.extra_fields {
+            let path = format!("extra_fields.{}", key);
+            builder = builder
+                .extra_field_value(key, value)
+                .map_err(|e| at(path, e.0))?;
+        }
+        for (name, value) in self.headers {
+            let path = format!("headers.{}", name);
+            builder = builder
+                .http_header(name, value)
+                .map_err(|e| at(path, e.0))?;
+        }
+        if let Some(tenant) = self.tenant {
+            builder = builder
+                .http_header("X-Scope-OrgID", tenant.trim())
+                .map_err(|e| at("tenant".into(), e.0))?;
+        }
+
+        let batching = self.batching;
+        if let Some(max) = batching.max_concurrent_requests {
+            builder = builder.max_concurrent_requests(max);
+        }
+        if let Some(window) = batching.dedup_window_ms {
+            builder = builder.dedup_window(Duration::from_millis(window));
+        }
+        builder = builder
+            .monotonic_timestamps(batching.monotonic_timestamps)
+            .single_stream(batching.single_stream);
+
+        let retry = self.retry;
+        let defaults = TaskOptions::default().retry;
+        builder = builder.retry_backoff(
+            retry
+                .initial_backoff_ms
+                .map_or(defaults.initial_backoff, Duration::from_millis),
+            retry
+                .max_backoff_ms
+                .map_or(defaults.max_backoff, Duration::from_millis),
+        );
+        if let Some(threshold) = retry.drop_after_ms {
+            builder = builder.drop_after_backoff(Duration::from_millis(threshold));
+        }
+
+        let http = self.http;
+        if let Some(timeout) = http.connect_timeout_ms {
+            builder = builder.connect_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = http.request_timeout_ms {
+            builder = builder.request_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = http.pool_idle_timeout_ms {
+            builder = builder.pool_idle_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(interval) = http.tcp_keepalive_ms {
+            builder = builder.tcp_keepalive(Duration::from_millis(interval));
+        }
+        builder = builder.http2_prior_knowledge(http.http2_prior_knowledge);
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        {
+            builder = apply_tls(builder, self.tls)?;
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn builder() {
+        let builder = config(json!({
+            "url": "http://loki:3100/",
+            "labels": {"host": "mine", "app": "web"},
+            "extra_fields": {"pid": 4242},
+            "headers": {"X-Custom": "value"},
+            "tenant": "tenant-1",
+            "batching": {"max_concurrent_requests": 4, "dedup_window_ms": 1000},
+            "retry": {"initial_backoff_ms": 100},
+            "http": {"connect_timeout_ms": 5000, "request_timeout_ms": 30000},
+            "format": {
+                "level_label": "severity",
+                "level_values": {"warn": "warning"},
+                "line_overflow": "split",
+                "timestamp_field": {"name": "ts", "format": "unix_millis"},
+            },
+        }))
+        .into_builder()
+        .unwrap();
+        assert_eq!(builder.loki_url.unwrap().as_str(), "http://loki:3100/");
+        assert_eq!(
+            builder.labels.finish("warning"),
+            r#"{app="web",host="mine",severity="warning"}"#,
+        );
+        assert_eq!(builder.http_headers["X-Custom"], "value");
+        assert_eq!(builder.http_headers["X-Scope-OrgID"], "tenant-1");
+        assert_eq!(
+            builder.task_options.connection.connect_timeout,
+            Some(Duration::from_secs(5)),
+        );
+        assert_eq!(
+            builder.task_options.request_timeout,
+            Some(Duration::from_secs(30)),
+        );
+        assert!(config(json!({})).into_builder().unwrap().loki_url.is_none());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |value| config(value).into_builder().err().unwrap().to_string();
+        assert_eq!(
+            error(json!({"labels": {"2k": "v"}})),
+            r#"config labels.2k: invalid label character '2' in key "2k""#,
+        );
+        assert_eq!(
+            error(json!({"url": "/loki"})),
+            "config url: invalid Loki URL",
+        );
+        assert_eq!(
+            error(json!({"format": {"level_values": {"fatal": "f"}}})),
+            r#"config format.level_values.fatal: unknown level "fatal""#,
+        );
+        assert!(error(json!({"format": {"level_values": {"error": ""}}}))
+            .starts_with(r#"config format.level_values.error: invalid level label value """#));
+        assert_eq!(
+            error(json!({"headers": {"X-Scope-OrgID": "a"}, "tenant": "b"})),
+            r#"config tenant: duplicate HTTP header "X-Scope-OrgID""#,
+        );
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        assert!(error(json!({"tls": {"ca_file": "/nonexistent/ca.pem"}}))
+            .starts_with(r#"config tls.ca_file: couldn't read "/nonexistent/ca.pem": "#));
+        assert!(serde_json::from_value::<Config>(json!({"lables": {}})).is_err());
+        assert!(
+            serde_json::from_value::<Config>(json!({"format": {"line_overflow": "wrap"}})).is_err()
+        );
+    }
+}
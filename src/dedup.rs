@@ -0,0 +1,165 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use super::timestamp::format_rfc3339;
+use super::LokiEvent;
+
+/// A run of log lines identical to the previously queued one.
+struct Run {
+    event: LokiEvent,
+    count: u64,
+    last: SystemTime,
+}
+
+/// Coalesces consecutive identical log lines of a stream.
+///
+/// The first line is queued as usual, the following identical lines are
+/// counted and queued as a single line annotated with `repeat_count`,
+/// `_first_timestamp` and `_last_timestamp` once a different line arrives or
+/// the window since the first repetition has passed.
+pub struct Dedup {
+    window: Duration,
+    last_message: Option<String>,
+    run: Option<Run>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Dedup {
+        Dedup {
+            window,
+            last_message: None,
+            run: None,
+        }
+    }
+    pub fn push(&mut self, event: LokiEvent, queue: &mut Vec<LokiEvent>) {
+        if self.last_message.as_deref() == Some(&event.message) {
+            if let Some(run) = &mut self.run {
+                if event.timestamp < run.event.timestamp + self.window {
+                    run.count += 1;
+                    run.last = event.timestamp;
+                    run.event.trigger_send |= event.trigger_send;
+                    return;
+                }
+                self.flush(queue);
+            }
+            self.run = Some(Run {
+                last: event.timestamp,
+                event,
+                count: 1,
+            });
+            return;
+        }
+        self.flush(queue);
+        self.last_message = Some(event.message.clone());
+        queue.push(event);
+    }
+    /// The time at which the current run should be flushed.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.run.as_ref().map(|r| r.event.timestamp + self.window)
+    }
+    pub fn flush_expired(&mut self, now: SystemTime, queue: &mut Vec<LokiEvent>) {
+        if self.deadline().map(|d| d <= now).unwrap_or(false) {
+            self.flush(queue);
+        }
+    }
+    pub fn flush(&mut self, queue: &mut Vec<LokiEvent>) {
+        if let Some(mut run) = self.run.take() {
+            let first = run.event.timestamp;
+            run.event.message = annotate(run.event.message, run.count, first, run.last);
+            queue.push(run.event);
+        }
+    }
+}
+
+fn annotate(mut message: String, count: u64, first: SystemTime, last: SystemTime) -> String {
+    let first = format_rfc3339(first);
+    let last = format_rfc3339(last);
+    let is_object = message.ends_with('}')
+        && serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&message).is_ok();
+    if !is_object {
+        // The line might have been truncated, so it's not necessarily JSON.
+        return format!(
+            "{} [repeated {} times between {} and {}]",
+            message, count, first, last,
+        );
+    }
+    // Append the fields to the JSON object in place to keep the original
+    // field order.
+    message.pop();
+    let sep = if message.trim_end() == "{" { "" } else { "," };
+    write!(
+        message,
+        r#"{}"repeat_count":{},"_first_timestamp":"{}","_last_timestamp":"{}"}}"#,
+        sep, count, first, last,
+    )
+    .unwrap();
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::Dedup;
+    use crate::LokiEvent;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+    use tracing_core::Level;
+
+    fn event(secs: u64, message: &str) -> LokiEvent {
+        LokiEvent {
+            trigger_send: true,
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            level: Level::INFO,
+            level_value: None,
+            message: message.into(),
+        }
+    }
+
+    fn messages(queue: &[LokiEvent]) -> Vec<&str> {
+        queue.iter().map(|e| &e.message[..]).collect()
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut dedup = Dedup::new(Duration::from_secs(10));
+        let mut queue = Vec::new();
+        dedup.push(event(0, r#"{"message":"retrying"}"#), &mut queue);
+        for secs in 1..5 {
+            dedup.push(event(secs, r#"{"message":"retrying"}"#), &mut queue);
+        }
+        assert_eq!(messages(&queue), [r#"{"message":"retrying"}"#]);
+        dedup.push(event(5, r#"{"message":"done"}"#), &mut queue);
+        assert_eq!(
+            messages(&queue),
+            [
+                r#"{"message":"retrying"}"#,
+                r#"{"message":"retrying","repeat_count":4,"_first_timestamp":"1970-01-01T00:00:01.000000000Z","_last_timestamp":"1970-01-01T00:00:04.000000000Z"}"#,
+                r#"{"message":"done"}"#,
+            ],
+        );
+        assert_eq!(queue[1].timestamp, UNIX_EPOCH + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn window() {
+        let mut dedup = Dedup::new(Duration::from_secs(10));
+        let mut queue = Vec::new();
+        for secs in 0..13 {
+            dedup.push(event(secs, "line"), &mut queue);
+        }
+        assert_eq!(dedup.deadline(), Some(UNIX_EPOCH + Duration::from_secs(21)));
+        assert_eq!(
+            messages(&queue),
+            [
+                "line",
+                "line [repeated 10 times between 1970-01-01T00:00:01.000000000Z and 1970-01-01T00:00:10.000000000Z]",
+            ],
+        );
+        dedup.flush_expired(SystemTime::UNIX_EPOCH + Duration::from_secs(20), &mut queue);
+        assert_eq!(queue.len(), 2);
+        dedup.flush_expired(SystemTime::UNIX_EPOCH + Duration::from_secs(21), &mut queue);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(dedup.deadline(), None);
+    }
+}
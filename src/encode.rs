@@ -0,0 +1,234 @@
+//! Protobuf encoding of Loki push requests.
+//!
+//! Instead of building a [`loki_api::logproto::PushRequest`] and encoding it,
+//! this writes the protobuf wire format directly from the queued log lines,
+//! avoiding copies of all the lines and labels.
+//!
+//! The encoded messages are:
+//!
+//! ```protobuf
+//! message PushRequest {
+//!   repeated StreamAdapter streams = 1;
+//! }
+//! message StreamAdapter {
+//!   string labels = 1;
+//!   repeated EntryAdapter entries = 2;
+//!   uint64 hash = 3;
+//! }
+//! message EntryAdapter {
+//!   google.protobuf.Timestamp timestamp = 1;
+//!   string line = 2;
+//! }
+//! ```
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use loki_api::prost::encoding::encode_key;
+use loki_api::prost::encoding::encode_varint;
+use loki_api::prost::encoding::encoded_len_varint;
+use loki_api::prost::encoding::key_len;
+use loki_api::prost::encoding::WireType;
+use loki_api::prost_types::Timestamp;
+use std::time::SystemTime;
+
+fn timestamp_len(timestamp: &Timestamp) -> usize {
+    let mut len = 0;
+    if timestamp.seconds != 0 {
+        len += key_len(1) + encoded_len_varint(timestamp.seconds as u64);
+    }
+    if timestamp.nanos != 0 {
+        len += key_len(2) + encoded_len_varint(timestamp.nanos as i64 as u64);
+    }
+    len
+}
+
+fn length_delimited_len(tag: u32, len: usize) -> usize {
+    key_len(tag) + encoded_len_varint(len as u64) + len
+}
+
+fn entry_len(timestamp: &Timestamp, line: &str) -> usize {
+    let mut len = length_delimited_len(1, timestamp_len(timestamp));
+    if !line.is_empty() {
+        len += length_delimited_len(2, line.len());
+    }
+    len
+}
+
+fn encode_length_delimited(tag: u32, len: usize, buf: &mut Vec<u8>) {
+    encode_key(tag, WireType::LengthDelimited, buf);
+    encode_varint(len as u64, buf);
+}
+
+/// Buffers for encoding push requests, reused across requests.
+pub struct Buffer {
+    encoded: Vec<u8>,
+    /// The compressed requests are split off, the allocation is reused once
+    /// they were sent.
+    snappy: BytesMut,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            encoded: Vec::new(),
+            snappy: BytesMut::new(),
+        }
+    }
+    /// Encode a push request consisting of `streams` (pairs of encoded
+    /// labels and their entries) and compress it.
+    ///
+    /// Streams without entries are skipped.
+    pub fn encode<'a, S, E>(&mut self, streams: S) -> Bytes
+    where
+        S: IntoIterator<Item = (&'a str, E)>,
+        E: IntoIterator<Item = (SystemTime, &'a str)> + Clone,
+    {
+        self.encoded.clear();
+        for (labels, entries) in streams {
+            let mut num_entries = 0;
+            let entries_len: usize = entries
+                .clone()
+                .into_iter()
+                .map(|(timestamp, line)| {
+                    num_entries += 1;
+                    length_delimited_len(2, entry_len(&timestamp.into(), line))
+                })
+                .sum();
+            if num_entries == 0 {
+                continue;
+            }
+            let buf = &mut self.encoded;
+            encode_length_delimited(1, length_delimited_len(1, labels.len()) + entries_len, buf);
+            encode_length_delimited(1, labels.len(), buf);
+            buf.extend_from_slice(labels.as_bytes());
+            for (timestamp, line) in entries {
+                let timestamp: Timestamp = timestamp.into();
+                encode_length_delimited(2, entry_len(&timestamp, line), buf);
+                encode_length_delimited(1, timestamp_len(&timestamp), buf);
+                if timestamp.seconds != 0 {
+                    encode_key(1, WireType::Varint, buf);
+                    encode_varint(timestamp.seconds as u64, buf);
+                }
+                if timestamp.nanos != 0 {
+                    encode_key(2, WireType::Varint, buf);
+                    encode_varint(timestamp.nanos as i64 as u64, buf);
+                }
+                if !line.is_empty() {
+                    encode_length_delimited(2, line.len(), buf);
+                    buf.extend_from_slice(line.as_bytes());
+                }
+            }
+            // Couldn't find documentation except for the promtail source code:
+            // https://github.com/grafana/loki/blob/8c06c546ab15a568f255461f10318dae37e022d3/clients/pkg/promtail/client/batch.go#L55-L58
+            //
+            // In the Go code, the hash value isn't initialized explicitly,
+            // hence it is set to 0, which isn't encoded at all in protobuf.
+        }
+        self.compress_encoded()
+    }
+    fn compress_encoded(&mut self) -> Bytes {
+        self.snappy
+            .resize(snap::raw::max_compress_len(self.encoded.len()), 0);
+        // Couldn't find documentation except for the promtail source code:
+        // https://github.com/grafana/loki/blob/8c06c546ab15a568f255461f10318dae37e022d3/clients/pkg/promtail/client/batch.go#L101
+        //
+        // In the Go code, `snappy.Encode` is used, which corresponds to the
+        // snappy block format, and not the snappy stream format. hence
+        // `snap::raw` instead of `snap::write` is needed.
+        let snappy_len = snap::raw::Encoder::new()
+            .compress(&self.encoded, &mut self.snappy)
+            .expect("snappy encoding is infallible");
+        self.snappy.truncate(snappy_len);
+        self.snappy.split().freeze()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Buffer;
+    use loki_api::logproto as loki;
+    use loki_api::prost::Message;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+
+    fn decode(snappy: &[u8]) -> loki::PushRequest {
+        let encoded = snap::raw::Decoder::new().decompress_vec(snappy).unwrap();
+        loki::PushRequest::decode(&encoded[..]).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let now = SystemTime::now();
+        let long_line = "x".repeat(1000);
+        let streams: Vec<(&str, Vec<(SystemTime, &str)>)> = vec![
+            (
+                r#"{host="mine",level="info"}"#,
+                vec![
+                    (now, r#"{"message":"hello"}"#),
+                    (UNIX_EPOCH, ""),
+                    (UNIX_EPOCH - Duration::from_millis(1500), "before"),
+                ],
+            ),
+            (r#"{host="mine",level="warn"}"#, vec![]),
+            (
+                r#"{host="mine",level="error"}"#,
+                vec![(now, &long_line[..])],
+            ),
+        ];
+        let mut buffer = Buffer::new();
+        let expected = loki::PushRequest {
+            streams: streams
+                .iter()
+                .filter(|(_, entries)| !entries.is_empty())
+                .map(|(labels, entries)| loki::StreamAdapter {
+                    labels: labels.to_string(),
+                    entries: entries
+                        .iter()
+                        .map(|&(timestamp, line)| loki::EntryAdapter {
+                            timestamp: Some(timestamp.into()),
+                            line: line.into(),
+                        })
+                        .collect(),
+                    hash: 0,
+                })
+                .collect(),
+        };
+        for _ in 0..2 {
+            let encoded = buffer.encode(
+                streams
+                    .iter()
+                    .map(|(labels, entries)| (*labels, entries.iter().copied())),
+            );
+            assert_eq!(decode(&encoded), expected);
+            let mut prost_encoded = Vec::new();
+            expected.encode(&mut prost_encoded).unwrap();
+            assert_eq!(
+                snap::raw::Decoder::new().decompress_vec(&encoded).unwrap(),
+                prost_encoded,
+            );
+        }
+    }
+
+    #[test]
+    fn reuses_allocation() {
+        let streams = [("{}", vec![(UNIX_EPOCH, "x".repeat(100))])];
+        let mut buffer = Buffer::new();
+        let mut encode = || {
+            buffer.encode(
+                streams
+                    .iter()
+                    .map(|(labels, entries)| (*labels, entries.iter().map(|(t, l)| (*t, &l[..])))),
+            )
+        };
+        let first = encode();
+        // The first request is still in flight, a new allocation is needed.
+        let second = encode();
+        assert_ne!(second.as_ptr(), first.as_ptr());
+        assert_eq!(first, second);
+        let ptr = second.as_ptr();
+        drop((first, second));
+        // Once the requests were sent, the allocation is used again.
+        assert_eq!(encode().as_ptr(), ptr);
+    }
+}
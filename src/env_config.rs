@@ -0,0 +1,153 @@
+use std::env::VarError;
+use url::Url;
+
+use super::builder;
+use super::Builder;
+use super::Error;
+use super::ErrorI;
+
+/// Split a comma-separated list of `key=value` pairs.
+///
+/// Keys and values are trimmed, empty entries are skipped. Values may contain
+/// `=`, but not `,`.
+fn parse_pairs(list: &str) -> Result<Vec<(&str, &str)>, ErrorI> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((key, value)) => Ok((key.trim(), value.trim())),
+            None => Err(ErrorI::MalformedEnvEntry(entry.into())),
+        })
+        .collect()
+}
+
+fn in_var(name: &str, error: ErrorI) -> Error {
+    Error(ErrorI::Env(name.into(), Box::new(error)))
+}
+
+/// Create a [`Builder`] from the environment variables starting with
+/// `prefix`, looked up using `var`.
+pub fn builder_from_env<F>(prefix: &str, var: F) -> Result<Builder, Error>
+where
+    F: Fn(&str) -> Result<String, VarError>,
+{
+    let get = |suffix: &str| {
+        let name = format!("{}_{}", prefix, suffix);
+        match var(&name) {
+            Ok(value) if value.trim().is_empty() => Ok(None),
+            Ok(value) => Ok(Some((name, value))),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(_)) => Err(in_var(&name, ErrorI::NotUnicode)),
+        }
+    };
+    let mut builder = builder();
+    if let Some((name, url)) = get("URL")? {
+        let url = Url::parse(url.trim()).map_err(|_| in_var(&name, ErrorI::InvalidLokiUrl))?;
+        builder = builder.loki_url(url);
+    }
+    if let Some((name, labels)) = get("LABELS")? {
+        for (key, value) in parse_pairs(&labels).map_err(|e| in_var(&name, e))? {
+            builder = builder.label(key, value).map_err(|e| in_var(&name, e.0))?;
+        }
+    }
+    if let Some((name, fields)) = get("EXTRA_FIELDS")? {
+        for (key, value) in parse_pairs(&fields).map_err(|e| in_var(&name, e))? {
+            builder = builder
+                .extra_field(key, value)
+                .map_err(|e| in_var(&name, e.0))?;
+        }
+    }
+    if let Some((name, headers)) = get("HEADERS")? {
+        for (key, value) in parse_pairs(&headers).map_err(|e| in_var(&name, e))? {
+            builder = builder
+                .http_header(key, value)
+                .map_err(|e| in_var(&name, e.0))?;
+        }
+    }
+    if let Some((name, tenant)) = get("TENANT")? {
+        builder = builder
+            .http_header("X-Scope-OrgID", tenant.trim())
+            .map_err(|e| in_var(&name, e.0))?;
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::builder_from_env;
+    use super::parse_pairs;
+    use std::collections::HashMap;
+    use std::env::VarError;
+    use std::ffi::OsString;
+
+    fn from_env(vars: &[(&str, &str)]) -> Result<crate::Builder, crate::Error> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        builder_from_env("APP_LOKI", |name| {
+            vars.get(name)
+                .map(|v| v.to_string())
+                .ok_or(VarError::NotPresent)
+        })
+    }
+
+    #[test]
+    fn pairs() {
+        assert_eq!(
+            parse_pairs(" env=prod, team = x ,,query=a=b,").unwrap(),
+            [("env", "prod"), ("team", "x"), ("query", "a=b")],
+        );
+        assert_eq!(parse_pairs("").unwrap(), []);
+        assert!(parse_pairs("env=prod,team").is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let builder = from_env(&[
+            ("APP_LOKI_URL", "http://loki:3100/"),
+            ("APP_LOKI_LABELS", "env=prod,team=x"),
+            ("APP_LOKI_EXTRA_FIELDS", "region=eu"),
+            ("APP_LOKI_HEADERS", "X-Custom=a=b"),
+            ("APP_LOKI_TENANT", "tenant-1"),
+            // Wrong prefix.
+            ("LOKI_LABELS", "ignored=true"),
+        ])
+        .unwrap();
+        assert_eq!(builder.loki_url.unwrap().as_str(), "http://loki:3100/");
+        assert_eq!(
+            builder.labels.finish_without_level(),
+            r#"{env="prod",team="x"}"#,
+        );
+        assert_eq!(builder.http_headers["X-Custom"], "a=b");
+        assert_eq!(builder.http_headers["X-Scope-OrgID"], "tenant-1");
+        assert!(from_env(&[]).unwrap().loki_url.is_none());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |vars: &[(&str, &str)]| from_env(vars).err().unwrap().to_string();
+        assert_eq!(
+            error(&[("APP_LOKI_LABELS", "env=prod,team")]),
+            r#"environment variable APP_LOKI_LABELS: expected `key=value`, got "team""#,
+        );
+        assert_eq!(
+            error(&[("APP_LOKI_LABELS", "env=prod,env=dev")]),
+            r#"environment variable APP_LOKI_LABELS: duplicate label key "env""#,
+        );
+        assert_eq!(
+            error(&[("APP_LOKI_URL", "/loki")]),
+            "environment variable APP_LOKI_URL: invalid Loki URL",
+        );
+        assert_eq!(
+            error(&[
+                ("APP_LOKI_HEADERS", "X-Scope-OrgID=a"),
+                ("APP_LOKI_TENANT", "b"),
+            ]),
+            r#"environment variable APP_LOKI_TENANT: duplicate HTTP header "X-Scope-OrgID""#,
+        );
+        let not_unicode =
+            builder_from_env("LOKI", |_| Err(VarError::NotUnicode(OsString::from("x"))));
+        assert_eq!(
+            not_unicode.err().unwrap().to_string(),
+            "environment variable LOKI_URL: not valid unicode",
+        );
+    }
+}
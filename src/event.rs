@@ -0,0 +1,657 @@
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use serde::Serializer;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing_core::field::Field;
+use tracing_core::field::Visit;
+use tracing_core::Level;
+use tracing_core::Metadata;
+
+use super::field_filter::FieldKind;
+use super::labels::LevelLabel;
+use super::line_limits::capped;
+use super::line_limits::truncate_field;
+use super::ExtraFields;
+use super::FieldFilter;
+use super::LineLimits;
+use super::LokiEvent;
+use super::TimestampFormat;
+
+/// A field value captured when a span or an event is recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+}
+
+impl Serialize for FieldValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FieldValue::Bool(v) => serializer.serialize_bool(*v),
+            FieldValue::I64(v) => serializer.serialize_i64(*v),
+            FieldValue::U64(v) => serializer.serialize_u64(*v),
+            FieldValue::F64(v) => serializer.serialize_f64(*v),
+            FieldValue::Str(v) => serializer.serialize_str(v),
+        }
+    }
+}
+
+/// Field values in the order they were recorded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldList {
+    fields: Vec<(&'static str, FieldValue)>,
+}
+
+impl FieldList {
+    pub fn new() -> FieldList {
+        Default::default()
+    }
+    pub fn push(&mut self, name: &'static str, value: FieldValue) {
+        self.fields.push((name, value));
+    }
+    /// Set the value of a field, replacing a previously recorded value.
+    pub fn insert(&mut self, name: &'static str, value: FieldValue) {
+        match self.fields.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((name, value)),
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &FieldValue)> {
+        self.fields.iter().map(|(n, v)| (*n, v))
+    }
+}
+
+/// Records fields into a [`FieldList`], applying the field filter and the
+/// field size limit.
+pub struct FieldRecorder<'a> {
+    pub fields: &'a mut FieldList,
+    pub target: &'a str,
+    pub filter: &'a FieldFilter,
+    pub max_field_size: Option<usize>,
+    /// Whether this records an event, as opposed to a span.
+    ///
+    /// Event fields are only recorded once, and the `log.` fields added by
+    /// `tracing-log` are stripped from them.
+    pub event: bool,
+}
+
+impl<'a> FieldRecorder<'a> {
+    fn kind(&self) -> FieldKind {
+        if self.event {
+            FieldKind::Event
+        } else {
+            FieldKind::Span
+        }
+    }
+    fn enabled(&self, field: &Field) -> bool {
+        !(self.event && field.name().starts_with("log."))
+            && self.filter.enabled(self.target, field.name(), self.kind())
+    }
+    fn record(&mut self, field: &Field, value: FieldValue) {
+        if self.event {
+            self.fields.push(field.name(), value);
+        } else {
+            self.fields.insert(field.name(), value);
+        }
+    }
+    fn record_string(&mut self, field: &Field, mut value: String) {
+        if let Some(max) = self.max_field_size {
+            truncate_field(&mut value, max);
+        }
+        self.record(field, FieldValue::Str(value));
+    }
+}
+
+impl<'a> Visit for FieldRecorder<'a> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.enabled(field) {
+            self.record_string(field, format!("{:?}", value));
+        }
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if self.enabled(field) {
+            self.record(field, FieldValue::F64(value));
+        }
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if self.enabled(field) {
+            self.record(field, FieldValue::I64(value));
+        }
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if self.enabled(field) {
+            self.record(field, FieldValue::U64(value));
+        }
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if self.enabled(field) {
+            self.record(field, FieldValue::Bool(value));
+        }
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.enabled(field) {
+            self.record_string(field, value.into());
+        }
+    }
+    fn record_error(&mut self, field: &Field, value: &(dyn error::Error + 'static)) {
+        if self.enabled(field) {
+            self.record_string(field, format!("{}", value));
+        }
+    }
+}
+
+/// The metadata of a captured event.
+#[derive(Clone)]
+pub enum EventMeta {
+    Tracing(&'static Metadata<'static>),
+    /// Metadata of events converted from the `log` crate, which doesn't live
+    /// long enough to be borrowed.
+    Log {
+        level: Level,
+        target: String,
+        module_path: Option<String>,
+        file: Option<String>,
+        line: Option<u32>,
+    },
+}
+
+impl EventMeta {
+    pub fn from_log(meta: &Metadata<'_>) -> EventMeta {
+        EventMeta::Log {
+            level: *meta.level(),
+            target: meta.target().into(),
+            module_path: meta.module_path().map(Into::into),
+            file: meta.file().map(Into::into),
+            line: meta.line(),
+        }
+    }
+    pub fn level(&self) -> Level {
+        match self {
+            EventMeta::Tracing(meta) => *meta.level(),
+            EventMeta::Log { level, .. } => *level,
+        }
+    }
+    pub fn target(&self) -> &str {
+        match self {
+            EventMeta::Tracing(meta) => meta.target(),
+            EventMeta::Log { target, .. } => target,
+        }
+    }
+    pub fn module_path(&self) -> Option<&str> {
+        match self {
+            EventMeta::Tracing(meta) => meta.module_path(),
+            EventMeta::Log { module_path, .. } => module_path.as_deref(),
+        }
+    }
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            EventMeta::Tracing(meta) => meta.file(),
+            EventMeta::Log { file, .. } => file.as_deref(),
+        }
+    }
+    pub fn line(&self) -> Option<u32> {
+        match self {
+            EventMeta::Tracing(meta) => meta.line(),
+            EventMeta::Log { line, .. } => *line,
+        }
+    }
+}
+
+/// The names and merged fields of a span and all its ancestors.
+///
+/// It is cached in the span's extensions so that events don't need to
+/// collect the fields of all their spans again.
+pub struct SpanContext {
+    parent: Option<Arc<SpanContext>>,
+    names: Vec<&'static str>,
+    /// Sorted by name, fields of inner spans take precedence over the ones of
+    /// outer spans.
+    fields: Vec<(&'static str, FieldValue)>,
+}
+
+impl SpanContext {
+    pub fn new(
+        parent: Option<Arc<SpanContext>>,
+        name: &'static str,
+        own_fields: &FieldList,
+    ) -> SpanContext {
+        let mut names = Vec::new();
+        let mut fields = BTreeMap::new();
+        if let Some(parent) = &parent {
+            names.extend_from_slice(&parent.names);
+            fields.extend(parent.fields.iter().cloned());
+        }
+        names.push(name);
+        fields.extend(own_fields.iter().map(|(n, v)| (n, v.clone())));
+        SpanContext {
+            parent,
+            names,
+            fields: fields.into_iter().collect(),
+        }
+    }
+    /// Whether this context was built on top of `parent`'s current context.
+    ///
+    /// If not, some ancestor's fields changed, and the context needs to be
+    /// rebuilt.
+    pub fn is_child_of(&self, parent: Option<&Arc<SpanContext>>) -> bool {
+        match (&self.parent, parent) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// An event as captured by the [`Layer`](crate::Layer), to be formatted by
+/// the [`BackgroundTask`](crate::BackgroundTask).
+pub struct CapturedEvent {
+    pub timestamp: SystemTime,
+    pub meta: EventMeta,
+    pub fields: FieldList,
+    pub dynamic_fields: Vec<serde_json::Value>,
+    /// The context of the innermost span the event occurred in.
+    pub spans: Option<Arc<SpanContext>>,
+}
+
+/// A field value with its string, if any, cut to at most the given number
+/// of bytes.
+struct CappedField<'a>(&'a FieldValue, Option<usize>);
+
+impl<'a> Serialize for CappedField<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.0, self.1) {
+            (FieldValue::Str(v), Some(max)) => serializer.serialize_str(&capped(v, max)),
+            (value, _) => value.serialize(serializer),
+        }
+    }
+}
+
+struct SerializedEvent<'a> {
+    event: &'a CapturedEvent,
+    extra_fields: &'a ExtraFields,
+    /// The name and value of the level field, if it's part of the line.
+    level: Option<(&'a str, &'a str)>,
+    /// The maximum length of string values, when truncating the line.
+    max_value_len: Option<usize>,
+    /// The length of the line before truncation, if it was truncated.
+    original_size: Option<usize>,
+}
+
+impl<'a> Serialize for SerializedEvent<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let event = self.event;
+        let mut map = serializer.serialize_map(None)?;
+        for (name, value) in event.fields.iter() {
+            // The level field replaces an event field of the same name, which
+            // may be the one overriding it.
+            if matches!(self.level, Some((level_name, _)) if level_name == name) {
+                continue;
+            }
+            map.serialize_entry(name, &CappedField(value, self.max_value_len))?;
+        }
+        self.extra_fields
+            .serialize_entries(&mut map, &event.dynamic_fields, self.max_value_len)?;
+        let (span_names, span_fields) = match &event.spans {
+            Some(spans) => (&spans.names[..], &spans.fields[..]),
+            None => (&[][..], &[][..]),
+        };
+        for (name, value) in span_fields {
+            map.serialize_entry(name, &CappedField(value, self.max_value_len))?;
+        }
+        if let Some((name, value)) = self.level {
+            map.serialize_entry(name, value)?;
+        }
+        map.serialize_entry("_spans", span_names)?;
+        map.serialize_entry("_target", event.meta.target())?;
+        map.serialize_entry("_module_path", &event.meta.module_path())?;
+        map.serialize_entry("_file", &event.meta.file())?;
+        map.serialize_entry("_line", &event.meta.line())?;
+        if let Some(size) = self.original_size {
+            map.serialize_entry("_truncated", &true)?;
+            map.serialize_entry("_original_size", &size)?;
+        }
+        map.end()
+    }
+}
+
+/// Formats captured events into log lines in the
+/// [`BackgroundTask`](crate::BackgroundTask).
+pub struct EventFormatter {
+    pub extra_fields: ExtraFields,
+    pub line_limits: LineLimits,
+    /// The event field overriding the timestamp, and its format.
+    pub timestamp_field: Option<(String, TimestampFormat)>,
+    pub level_label: LevelLabel,
+    /// Whether to include the level in the line, because it's not part of
+    /// the labels.
+    pub level_in_line: bool,
+}
+
+impl EventFormatter {
+    pub fn format<F: FnMut(LokiEvent)>(&self, event: CapturedEvent, mut emit: F) {
+        let level = event.meta.level();
+        let level_override = self.level_label.field.as_ref().and_then(|name| {
+            match event.fields.iter().find(|(n, _)| n == name)? {
+                (_, FieldValue::Str(value)) => Some(&value[..]),
+                _ => None,
+            }
+        });
+        let level_value = level_override.unwrap_or(&self.level_label.values[level]);
+        let serialize = |max_value_len, original_size| {
+            serde_json::to_string(&SerializedEvent {
+                event: &event,
+                extra_fields: &self.extra_fields,
+                level: Some((&self.level_label.name[..], level_value))
+                    .filter(|_| self.level_in_line),
+                max_value_len,
+                original_size,
+            })
+            .expect("json serialization shouldn't fail")
+        };
+        let message = serialize(None, None);
+        let trigger_send = !event.meta.target().starts_with("tracing_loki");
+        let level_value = level_override
+            .filter(|_| !self.level_in_line)
+            .map(String::from);
+        let timestamp = self
+            .timestamp_field
+            .as_ref()
+            .and_then(|(name, format)| {
+                let (_, value) = event.fields.iter().find(|(n, _)| n == name)?;
+                format.parse(value)
+            })
+            .unwrap_or(event.timestamp);
+        let truncated =
+            |max_value_len, original_size| serialize(Some(max_value_len), Some(original_size));
+        self.line_limits
+            .apply(message, truncated, |offset, message| {
+                emit(LokiEvent {
+                    trigger_send,
+                    timestamp: timestamp + offset,
+                    level,
+                    level_value: level_value.clone(),
+                    message,
+                })
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CapturedEvent;
+    use super::EventFormatter;
+    use super::EventMeta;
+    use super::FieldList;
+    use super::FieldValue;
+    use super::SpanContext;
+    use crate::labels::LevelLabel;
+    use crate::ExtraFields;
+    use crate::LineLimits;
+    use crate::LineOverflow;
+    use crate::TimestampFormat;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+    use tracing_core::Level;
+
+    #[test]
+    fn format() {
+        let mut extra_fields = ExtraFields::new();
+        extra_fields.add_static("pid".into(), json!(1234)).unwrap();
+        extra_fields
+            .add_dynamic("flag".into(), Arc::new(|| json!(true)))
+            .unwrap();
+        let formatter = EventFormatter {
+            extra_fields,
+            line_limits: LineLimits::default(),
+            timestamp_field: None,
+            level_label: LevelLabel::default(),
+            level_in_line: false,
+        };
+        let mut event_fields = FieldList::new();
+        event_fields.push("message", FieldValue::Str("hello".into()));
+        event_fields.push("count", FieldValue::U64(3));
+        let mut outer = FieldList::new();
+        outer.insert("request", FieldValue::I64(-1));
+        outer.insert("user", FieldValue::Str("alice".into()));
+        let mut inner = FieldList::new();
+        inner.insert("user", FieldValue::Str("bob".into()));
+        inner.insert("ratio", FieldValue::F64(0.5));
+        let event = CapturedEvent {
+            timestamp: UNIX_EPOCH,
+            meta: EventMeta::Log {
+                level: Level::WARN,
+                target: "app".into(),
+                module_path: Some("app::module".into()),
+                file: None,
+                line: Some(12),
+            },
+            fields: event_fields,
+            dynamic_fields: vec![json!(false)],
+            spans: Some(Arc::new(SpanContext::new(
+                Some(Arc::new(SpanContext::new(None, "outer", &outer))),
+                "inner",
+                &inner,
+            ))),
+        };
+        let mut result = Vec::new();
+        formatter.format(event, |e| result.push(e));
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].level, Level::WARN);
+        assert!(result[0].trigger_send);
+        assert_eq!(
+            result[0].message,
+            concat!(
+                r#"{"message":"hello","count":3,"pid":1234,"flag":false,"#,
+                r#""ratio":0.5,"request":-1,"user":"bob","_spans":["outer","inner"],"#,
+                r#""_target":"app","_module_path":"app::module","_file":null,"_line":12}"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn level_in_line() {
+        let formatter = EventFormatter {
+            extra_fields: ExtraFields::new(),
+            line_limits: LineLimits::default(),
+            timestamp_field: None,
+            level_label: LevelLabel::default(),
+            level_in_line: true,
+        };
+        let mut fields = FieldList::new();
+        fields.push("message", FieldValue::Str("hello".into()));
+        let event = CapturedEvent {
+            timestamp: UNIX_EPOCH,
+            meta: EventMeta::Log {
+                level: Level::DEBUG,
+                target: "app".into(),
+                module_path: None,
+                file: None,
+                line: None,
+            },
+            fields,
+            dynamic_fields: Vec::new(),
+            spans: None,
+        };
+        let mut result = Vec::new();
+        formatter.format(event, |e| result.push(e));
+        assert_eq!(
+            result[0].message,
+            concat!(
+                r#"{"message":"hello","level":"debug","_spans":[],"_target":"app","#,
+                r#""_module_path":null,"_file":null,"_line":null}"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn level_override() {
+        let mut level_label = LevelLabel {
+            name: "severity".into(),
+            field: Some("severity".into()),
+            ..LevelLabel::default()
+        };
+        level_label.values[Level::ERROR] = "err".into();
+        let mut formatter = EventFormatter {
+            extra_fields: ExtraFields::new(),
+            line_limits: LineLimits::default(),
+            timestamp_field: None,
+            level_label,
+            level_in_line: false,
+        };
+        let event = |severity: Option<&str>| {
+            let mut fields = FieldList::new();
+            if let Some(severity) = severity {
+                fields.push("severity", FieldValue::Str(severity.into()));
+            }
+            CapturedEvent {
+                timestamp: UNIX_EPOCH,
+                meta: EventMeta::Log {
+                    level: Level::ERROR,
+                    target: "app".into(),
+                    module_path: None,
+                    file: None,
+                    line: None,
+                },
+                fields,
+                dynamic_fields: Vec::new(),
+                spans: None,
+            }
+        };
+        let format = |formatter: &EventFormatter, severity| {
+            let mut result = Vec::new();
+            formatter.format(event(severity), |e| result.push(e));
+            result.pop().unwrap()
+        };
+        let e = format(&formatter, Some("critical"));
+        assert_eq!(e.level_value.as_deref(), Some("critical"));
+        assert_eq!(e.level, Level::ERROR);
+        assert_eq!(format(&formatter, None).level_value, None);
+
+        formatter.level_in_line = true;
+        let e = format(&formatter, Some("critical"));
+        assert_eq!(e.level_value, None);
+        assert!(e.message.starts_with(r#"{"severity":"critical","_spans""#));
+        let e = format(&formatter, None);
+        assert!(e.message.starts_with(r#"{"severity":"err","_spans""#));
+    }
+
+    #[test]
+    fn line_limits() {
+        let mut formatter = EventFormatter {
+            extra_fields: ExtraFields::new(),
+            line_limits: LineLimits {
+                max_line_size: Some(200),
+                ..LineLimits::default()
+            },
+            timestamp_field: None,
+            level_label: LevelLabel::default(),
+            level_in_line: false,
+        };
+        let mut fields = FieldList::new();
+        fields.push("message", FieldValue::Str("x".repeat(300)));
+        fields.push("count", FieldValue::U64(3));
+        let event = || CapturedEvent {
+            timestamp: UNIX_EPOCH,
+            meta: EventMeta::Log {
+                level: Level::INFO,
+                target: "app".into(),
+                module_path: None,
+                file: None,
+                line: None,
+            },
+            fields: fields.clone(),
+            dynamic_fields: Vec::new(),
+            spans: None,
+        };
+        let mut result = Vec::new();
+        formatter.format(event(), |e| result.push(e));
+        assert_eq!(result.len(), 1);
+        assert!(result[0].message.len() <= 200);
+        let line: serde_json::Value = serde_json::from_str(&result[0].message).unwrap();
+        assert_eq!(line["count"], 3);
+        assert_eq!(line["_target"], "app");
+        assert_eq!(line["_truncated"], true);
+        assert_eq!(line["_original_size"], 398);
+        assert!(line["message"].as_str().unwrap().ends_with("x…"));
+
+        formatter.line_limits.overflow = LineOverflow::Split;
+        let mut result = Vec::new();
+        formatter.format(event(), |e| result.push(e));
+        assert!(result.len() > 1);
+        let mut joined = String::new();
+        for (i, e) in result.iter().enumerate() {
+            assert_eq!(e.timestamp, UNIX_EPOCH + Duration::from_nanos(i as u64));
+            let part: serde_json::Value = serde_json::from_str(&e.message).unwrap();
+            joined.push_str(part["_chunk"].as_str().unwrap());
+        }
+        let line: serde_json::Value = serde_json::from_str(&joined).unwrap();
+        assert_eq!(line["message"].as_str().unwrap().len(), 300);
+    }
+
+    #[test]
+    fn timestamp_field() {
+        let formatter = EventFormatter {
+            extra_fields: ExtraFields::new(),
+            line_limits: LineLimits::default(),
+            timestamp_field: Some(("ts".into(), TimestampFormat::UnixMillis)),
+            level_label: LevelLabel::default(),
+            level_in_line: false,
+        };
+        let event = |ts: Option<FieldValue>| {
+            let mut fields = FieldList::new();
+            fields.push("message", FieldValue::Str("replayed".into()));
+            if let Some(ts) = ts {
+                fields.push("ts", ts);
+            }
+            CapturedEvent {
+                timestamp: UNIX_EPOCH + Duration::from_secs(100),
+                meta: EventMeta::Log {
+                    level: Level::INFO,
+                    target: "app".into(),
+                    module_path: None,
+                    file: None,
+                    line: None,
+                },
+                fields,
+                dynamic_fields: Vec::new(),
+                spans: None,
+            }
+        };
+        let timestamp = |ts| {
+            let mut result = Vec::new();
+            formatter.format(event(ts), |e| result.push(e));
+            result[0].timestamp
+        };
+        assert_eq!(
+            timestamp(Some(FieldValue::U64(1500))),
+            UNIX_EPOCH + Duration::from_millis(1500),
+        );
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(timestamp(Some(FieldValue::Str("yesterday".into()))), now);
+        assert_eq!(timestamp(None), now);
+    }
+
+    #[test]
+    fn span_context() {
+        let mut fields = FieldList::new();
+        fields.insert("a", FieldValue::U64(1));
+        let root = Arc::new(SpanContext::new(None, "root", &fields));
+        assert!(root.is_child_of(None));
+        let child = SpanContext::new(Some(root.clone()), "child", &FieldList::new());
+        assert!(child.is_child_of(Some(&root)));
+        assert!(!child.is_child_of(None));
+        let new_root = Arc::new(SpanContext::new(None, "root", &fields));
+        assert!(!child.is_child_of(Some(&new_root)));
+        assert_eq!(child.names, ["root", "child"]);
+        assert_eq!(child.fields, [("a", FieldValue::U64(1))]);
+    }
+}
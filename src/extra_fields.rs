@@ -0,0 +1,130 @@
+use serde::ser::SerializeMap;
+use std::sync::Arc;
+
+use super::line_limits::CappedValue;
+use super::Error;
+use super::ErrorI;
+
+#[derive(Clone)]
+enum ExtraField {
+    Static(serde_json::Value),
+    Dynamic(Arc<dyn Fn() -> serde_json::Value + Send + Sync>),
+}
+
+#[derive(Clone, Default)]
+pub struct ExtraFields {
+    fields: Vec<(String, ExtraField)>,
+}
+
+impl ExtraFields {
+    pub fn new() -> ExtraFields {
+        Default::default()
+    }
+    fn add(&mut self, key: String, field: ExtraField) -> Result<(), Error> {
+        if self.fields.iter().any(|(k, _)| *k == key) {
+            return Err(Error(ErrorI::DuplicateExtraField(key)));
+        }
+        self.fields.push((key, field));
+        Ok(())
+    }
+    pub fn add_static(&mut self, key: String, value: serde_json::Value) -> Result<(), Error> {
+        self.add(key, ExtraField::Static(value))
+    }
+    pub fn add_dynamic(
+        &mut self,
+        key: String,
+        f: Arc<dyn Fn() -> serde_json::Value + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.add(key, ExtraField::Dynamic(f))
+    }
+    /// Compute the values of the dynamic extra fields, in order.
+    ///
+    /// This needs to happen when the event is emitted.
+    pub fn capture_dynamic(&self) -> Vec<serde_json::Value> {
+        self.fields
+            .iter()
+            .filter_map(|(_, field)| match field {
+                ExtraField::Static(_) => None,
+                ExtraField::Dynamic(f) => Some(f()),
+            })
+            .collect()
+    }
+    /// Serialize all extra fields, using the values previously obtained from
+    /// [`ExtraFields::capture_dynamic`] for the dynamic ones.
+    ///
+    /// Strings in the values are cut to `max_value_len` bytes, if given.
+    pub fn serialize_entries<M: SerializeMap>(
+        &self,
+        map: &mut M,
+        dynamic: &[serde_json::Value],
+        max_value_len: Option<usize>,
+    ) -> Result<(), M::Error> {
+        let mut dynamic = dynamic.iter();
+        for (key, field) in &self.fields {
+            let value = match field {
+                ExtraField::Static(value) => value,
+                ExtraField::Dynamic(_) => match dynamic.next() {
+                    Some(value) => value,
+                    None => continue,
+                },
+            };
+            map.serialize_entry(key, &CappedValue(value, max_value_len))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExtraFields;
+    use serde::ser::SerializeMap;
+    use serde::Serialize;
+    use serde::Serializer;
+    use serde_json::json;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    struct Captured<'a>(&'a ExtraFields, Vec<serde_json::Value>);
+
+    impl<'a> Serialize for Captured<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(None)?;
+            self.0.serialize_entries(&mut map, &self.1, None)?;
+            map.end()
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut fields = ExtraFields::new();
+        fields.add_static("pid".into(), json!(1234)).unwrap();
+        fields
+            .add_dynamic("requests".into(), {
+                let counter = counter.clone();
+                Arc::new(move || counter.fetch_add(1, Ordering::Relaxed).into())
+            })
+            .unwrap();
+        let first = fields.capture_dynamic();
+        let second = fields.capture_dynamic();
+        assert_eq!(
+            serde_json::to_string(&Captured(&fields, first)).unwrap(),
+            r#"{"pid":1234,"requests":0}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&Captured(&fields, second)).unwrap(),
+            r#"{"pid":1234,"requests":1}"#,
+        );
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut fields = ExtraFields::new();
+        fields.add_static("key".into(), json!("value")).unwrap();
+        assert!(fields.add_static("key".into(), json!(true)).is_err());
+        assert!(fields
+            .add_dynamic("key".into(), Arc::new(|| json!(null)))
+            .is_err());
+    }
+}
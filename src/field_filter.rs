@@ -0,0 +1,237 @@
+/// Whether a field belongs to a span or to an event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldKind {
+    Span,
+    Event,
+}
+
+/// A field name pattern, compiled when the rule is added.
+///
+/// The common shapes get their own variants so that they can be matched
+/// without walking the pattern.
+#[derive(Clone, Debug, PartialEq)]
+enum Pattern {
+    Any,
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Glob(String),
+}
+
+impl Pattern {
+    fn new(pattern: String) -> Pattern {
+        if pattern.contains('?') {
+            return Pattern::Glob(pattern);
+        }
+        match pattern.matches('*').count() {
+            0 => Pattern::Exact(pattern),
+            _ if pattern.bytes().all(|b| b == b'*') => Pattern::Any,
+            1 if pattern.ends_with('*') => Pattern::Prefix(pattern[..pattern.len() - 1].into()),
+            1 if pattern.starts_with('*') => Pattern::Suffix(pattern[1..].into()),
+            _ => Pattern::Glob(pattern),
+        }
+    }
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Exact(p) => text == p,
+            Pattern::Prefix(p) => text.starts_with(&p[..]),
+            Pattern::Suffix(p) => text.ends_with(&p[..]),
+            Pattern::Glob(p) => glob_match(p, text),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rule {
+    target_prefix: String,
+    pattern: Pattern,
+    allow: bool,
+    spans_only: bool,
+}
+
+/// Allow and deny lists for span and event fields, scoped by target prefix.
+///
+/// A field is dropped if any deny rule matching the target matches its name.
+/// If there are allow rules matching the target, the field is only kept if
+/// at least one of them matches its name.
+///
+/// The `message` field of events is only affected by rules naming it
+/// exactly, so that e.g. denying `*` doesn't empty the log lines.
+#[derive(Clone, Default)]
+pub struct FieldFilter {
+    rules: Vec<Rule>,
+}
+
+impl FieldFilter {
+    pub fn new() -> FieldFilter {
+        Default::default()
+    }
+    pub fn allow(&mut self, target_prefix: String, pattern: String, spans_only: bool) {
+        self.rules.push(Rule {
+            target_prefix,
+            pattern: Pattern::new(pattern),
+            allow: true,
+            spans_only,
+        });
+    }
+    pub fn deny(&mut self, target_prefix: String, pattern: String, spans_only: bool) {
+        self.rules.push(Rule {
+            target_prefix,
+            pattern: Pattern::new(pattern),
+            allow: false,
+            spans_only,
+        });
+    }
+    pub fn enabled(&self, target: &str, field: &str, kind: FieldKind) -> bool {
+        let message = kind == FieldKind::Event && field == "message";
+        let mut has_allow = false;
+        let mut allowed = false;
+        for rule in &self.rules {
+            if (rule.spans_only && kind != FieldKind::Span)
+                || !target.starts_with(&rule.target_prefix)
+            {
+                continue;
+            }
+            if message && !matches!(&rule.pattern, Pattern::Exact(p) if p == "message") {
+                continue;
+            }
+            let matches = rule.pattern.matches(field);
+            if rule.allow {
+                has_allow = true;
+                allowed |= matches;
+            } else if matches {
+                return false;
+            }
+        }
+        !has_allow || allowed
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any sequence of
+/// characters and `?` matches any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, bytes) = (pattern.as_bytes(), text.as_bytes());
+    // The length of the character starting at byte `t` of the text, which is
+    // always at a character boundary when this is called.
+    let char_len = |t: usize| text[t..].chars().next().map_or(1, char::len_utf8);
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it
+    // currently matches up to, for backtracking.
+    let mut star: Option<(usize, usize)> = None;
+    while t < bytes.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += char_len(t);
+            }
+            Some(&c) if c == bytes[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    let star_t = star_t + char_len(star_t);
+                    star = Some((star_p, star_t));
+                    p = star_p + 1;
+                    t = star_t;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_match;
+    use super::FieldFilter;
+    use super::FieldKind::Event;
+    use super::FieldKind::Span;
+    use super::Pattern;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("abc", "abc"));
+        assert!(!glob_match("abc", "abcd"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a*c", "abbbc"));
+        assert!(glob_match("a*c", "ac"));
+        assert!(!glob_match("a*c", "acb"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("http.*", "http.method"));
+        assert!(glob_match("*.*.*", "a.b.c"));
+        assert!(!glob_match("*.*.*", "a.b"));
+        assert!(glob_match("a?c", "aäc"));
+        assert!(glob_match("*ä?", "xäyäz"));
+        assert!(!glob_match("ä", "ö"));
+    }
+
+    #[test]
+    fn pattern() {
+        let matches = |pattern: &str, text| Pattern::new(pattern.into()).matches(text);
+        assert_eq!(Pattern::new("**".into()), Pattern::Any);
+        assert_eq!(Pattern::new("db.*".into()), Pattern::Prefix("db.".into()));
+        assert_eq!(Pattern::new("*_id".into()), Pattern::Suffix("_id".into()));
+        assert_eq!(Pattern::new("id".into()), Pattern::Exact("id".into()));
+        assert!(matches("db.*", "db.rows"));
+        assert!(!matches("db.*", "db"));
+        assert!(matches("*_id", "user_id"));
+        assert!(!matches("*_id", "id"));
+        assert!(matches("a*b*c", "abc"));
+        assert!(matches("a?", "ab"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(FieldFilter::new().enabled("hyper::client", "anything", Event));
+    }
+
+    #[test]
+    fn deny() {
+        let mut filter = FieldFilter::new();
+        filter.deny("hyper".into(), "*".into(), false);
+        filter.deny("".into(), "password".into(), false);
+        assert!(!filter.enabled("hyper::client", "method", Event));
+        assert!(filter.enabled("reqwest", "method", Event));
+        assert!(!filter.enabled("reqwest", "password", Event));
+    }
+
+    #[test]
+    fn allow() {
+        let mut filter = FieldFilter::new();
+        filter.allow("sqlx".into(), "db.*".into(), false);
+        filter.deny("sqlx".into(), "db.statement".into(), false);
+        assert!(filter.enabled("sqlx::query", "db.rows", Event));
+        assert!(!filter.enabled("sqlx::query", "db.statement", Event));
+        assert!(!filter.enabled("sqlx::query", "summary", Event));
+        assert!(filter.enabled("app", "summary", Event));
+    }
+
+    #[test]
+    fn spans_only() {
+        let mut filter = FieldFilter::new();
+        filter.deny("hyper".into(), "*".into(), true);
+        assert!(!filter.enabled("hyper::client", "method", Span));
+        assert!(filter.enabled("hyper::client", "method", Event));
+    }
+
+    #[test]
+    fn message() {
+        let mut filter = FieldFilter::new();
+        filter.deny("hyper".into(), "*".into(), false);
+        filter.allow("sqlx".into(), "db.*".into(), false);
+        assert!(filter.enabled("hyper::client", "message", Event));
+        assert!(!filter.enabled("hyper::client", "message", Span));
+        assert!(filter.enabled("sqlx::query", "message", Event));
+        filter.deny("noisy".into(), "message".into(), false);
+        assert!(!filter.enabled("noisy", "message", Event));
+    }
+}
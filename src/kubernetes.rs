@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Path of the namespace file of the service account mounted into pods.
+const NAMESPACE_PATH: &str = "var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// Detector for Kubernetes and container metadata, see
+/// [`Builder::kubernetes`](crate::Builder::kubernetes).
+///
+/// It reads the following, relative to the filesystem root (`/` by default):
+///
+/// - `namespace`: the `POD_NAMESPACE` environment variable, or the namespace
+///   of the pod's service account from
+///   `/var/run/secrets/kubernetes.io/serviceaccount/namespace`.
+/// - `pod`: the `POD_NAME` environment variable, or `HOSTNAME`, which
+///   Kubernetes sets to the pod name.
+/// - `container`: the `CONTAINER_NAME` environment variable.
+/// - `node`: the `NODE_NAME` environment variable.
+/// - `container_id`: parsed from `/proc/self/cgroup`.
+///
+/// Except for `HOSTNAME`, these environment variables need to be set using
+/// the [downward API](https://kubernetes.io/docs/concepts/workloads/pods/downward-api/)
+/// in the pod spec, e.g.
+///
+/// ```yaml
+/// env:
+///   - name: POD_NAME
+///     valueFrom:
+///       fieldRef:
+///         fieldPath: metadata.name
+///   - name: NODE_NAME
+///     valueFrom:
+///       fieldRef:
+///         fieldPath: spec.nodeName
+/// ```
+#[derive(Clone, Debug)]
+pub struct Kubernetes {
+    root: PathBuf,
+    env: Option<HashMap<String, String>>,
+}
+
+/// The metadata found by [`Kubernetes`].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct KubernetesMetadata {
+    pub labels: Vec<(&'static str, String)>,
+    pub container_id: Option<String>,
+}
+
+impl Default for Kubernetes {
+    fn default() -> Kubernetes {
+        Kubernetes::new()
+    }
+}
+
+impl Kubernetes {
+    /// Create a detector reading from the real filesystem and environment.
+    pub fn new() -> Kubernetes {
+        Kubernetes {
+            root: PathBuf::from("/"),
+            env: None,
+        }
+    }
+    /// Read files relative to `root` instead of `/`.
+    ///
+    /// This is mostly useful for testing.
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> Kubernetes {
+        self.root = root.into();
+        self
+    }
+    /// Look up environment variables in `vars` instead of the process
+    /// environment.
+    ///
+    /// This is mostly useful for testing.
+    pub fn env<I, K, V>(mut self, vars: I) -> Kubernetes
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env = Some(
+            vars.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        );
+        self
+    }
+    fn var(&self, name: &str) -> Option<String> {
+        match &self.env {
+            Some(vars) => vars.get(name).cloned(),
+            None => env::var(name).ok(),
+        }
+        .filter(|v| !v.is_empty())
+    }
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(Path::new(path))).ok()
+    }
+    /// Detect the metadata. Nothing is detected outside of Kubernetes, i.e.
+    /// when the namespace is unknown.
+    pub(crate) fn detect(&self) -> KubernetesMetadata {
+        let namespace = self.var("POD_NAMESPACE").or_else(|| {
+            Some(self.read(NAMESPACE_PATH)?.trim().to_owned()).filter(|n| !n.is_empty())
+        });
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => return KubernetesMetadata::default(),
+        };
+        let mut labels = vec![("namespace", namespace)];
+        let pod = self.var("POD_NAME").or_else(|| self.var("HOSTNAME"));
+        for (key, value) in [
+            ("pod", pod),
+            ("container", self.var("CONTAINER_NAME")),
+            ("node", self.var("NODE_NAME")),
+        ] {
+            if let Some(value) = value {
+                labels.push((key, value));
+            }
+        }
+        KubernetesMetadata {
+            labels,
+            container_id: self
+                .read("proc/self/cgroup")
+                .and_then(|c| parse_container_id(&c)),
+        }
+    }
+}
+
+/// Find the container ID in the contents of `/proc/self/cgroup`.
+///
+/// The last path component of a cgroup typically ends with the 64 hex digit
+/// container ID, e.g.
+///
+/// - `12:memory:/kubepods/burstable/pod<uid>/<id>` (cgroup v1),
+/// - `0::/kubepods.slice/…/cri-containerd-<id>.scope` (systemd),
+/// - `0::/docker/<id>`.
+///
+/// With cgroup namespaces, which are the default for cgroup v2, the path is
+/// just `/` and the ID can't be found.
+fn parse_container_id(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        let name = path.rsplit('/').next()?;
+        let name = name.strip_suffix(".scope").unwrap_or(name);
+        let id = name.rsplit('-').next()?;
+        let is_id = id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit());
+        Some(id.to_owned()).filter(|_| is_id)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_container_id;
+    use super::Kubernetes;
+    use super::KubernetesMetadata;
+    use super::NAMESPACE_PATH;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const ID: &str = "3f9b2c5d8e1a4b7c0d3e6f9a2b5c8d1e4f7a0b3c6d9e2f5a8b1c4d7e0f3a6b9c";
+
+    #[test]
+    fn container_id() {
+        for cgroup in [
+            format!("12:memory:/kubepods/burstable/pod1234/{}\n", ID),
+            format!(
+                "0::/kubepods.slice/kubepods-pod1234.slice/cri-containerd-{}.scope\n",
+                ID,
+            ),
+            format!("1:name=systemd:/user.slice\n0::/docker/{}\n", ID),
+        ] {
+            assert_eq!(
+                parse_container_id(&cgroup).as_deref(),
+                Some(ID),
+                "{}",
+                cgroup
+            );
+        }
+        assert_eq!(parse_container_id("0::/\n"), None);
+        assert_eq!(parse_container_id("0::/user.slice/session-2.scope\n"), None);
+        assert_eq!(parse_container_id(""), None);
+    }
+
+    #[test]
+    fn detect() {
+        let root = env::temp_dir().join(format!("tracing-loki-kubernetes-{}", process::id()));
+        let namespace_path = root.join(NAMESPACE_PATH);
+        fs::create_dir_all(namespace_path.parent().unwrap()).unwrap();
+        fs::create_dir_all(root.join("proc/self")).unwrap();
+        fs::write(&namespace_path, "production\n").unwrap();
+        fs::write(
+            root.join("proc/self/cgroup"),
+            format!("0::/docker/{}\n", ID),
+        )
+        .unwrap();
+
+        let detector = Kubernetes::new()
+            .root(&root)
+            .env([("HOSTNAME", "web-5d8f7"), ("NODE_NAME", "node-1")]);
+        let metadata = detector.detect();
+        let outside = Kubernetes::new()
+            .root(root.join("nonexistent"))
+            .env([("HOSTNAME", "laptop")])
+            .detect();
+        let overridden = detector
+            .clone()
+            .env([("POD_NAMESPACE", "staging"), ("POD_NAME", "web-0")])
+            .detect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            metadata,
+            KubernetesMetadata {
+                labels: vec![
+                    ("namespace", "production".into()),
+                    ("pod", "web-5d8f7".into()),
+                    ("node", "node-1".into()),
+                ],
+                container_id: Some(ID.into()),
+            },
+        );
+        assert_eq!(outside, KubernetesMetadata::default());
+        assert_eq!(
+            overridden.labels,
+            [("namespace", "staging".into()), ("pod", "web-0".into())],
+        );
+    }
+}
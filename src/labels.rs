@@ -2,13 +2,119 @@ use std::collections::HashSet;
 use std::fmt::Write as _;
 use tracing_core::Level;
 
+use super::line_limits::floor_char_boundary;
 use super::Error;
 use super::ErrorI;
+use super::LevelMap;
+
+/// Loki's default `max_label_name_length`.
+const MAX_NAME_LEN: usize = 1024;
+/// Loki's default `max_label_value_length`.
+const MAX_VALUE_LEN: usize = 2048;
+/// Loki's default `max_label_names_per_series`.
+pub const MAX_LABELS: usize = 15;
+
+fn is_valid_key_byte(i: usize, b: u8) -> bool {
+    match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'_' => true,
+        b'0'..=b'9' => i != 0,
+        _ => false,
+    }
+}
+
+/// Check a label name against the Prometheus data model, see
+/// <https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels>:
+/// it must match `[a-zA-Z_][a-zA-Z0-9_]*` and names starting with `__` are
+/// reserved for internal use.
+fn validate_key(key: String) -> Result<String, Error> {
+    if key.is_empty() {
+        return Err(Error(ErrorI::EmptyLabelName));
+    }
+    if let Some(i) = key
+        .bytes()
+        .enumerate()
+        .position(|(i, b)| !is_valid_key_byte(i, b))
+    {
+        // The first invalid byte must start a UTF-8 character, since all
+        // valid bytes are ASCII.
+        let c = key[i..].chars().next().unwrap();
+        return Err(Error(ErrorI::InvalidLabelCharacter(key, c)));
+    }
+    if key.starts_with("__") {
+        return Err(Error(ErrorI::ReservedLabelName(key)));
+    }
+    if key.len() > MAX_NAME_LEN {
+        return Err(Error(ErrorI::LabelNameTooLong(key)));
+    }
+    Ok(key)
+}
+
+/// Rewrite a label name so that it passes [`validate_key`].
+///
+/// Invalid characters are replaced by `_`, a leading digit gets prefixed by
+/// `_`, a leading `__` is shortened to `_` and overlong names are truncated.
+fn sanitize_key(key: &str) -> String {
+    let mut result: String = key
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    if result.is_empty() || result.as_bytes()[0].is_ascii_digit() {
+        result.insert(0, '_');
+    }
+    let underscores = result.bytes().take_while(|&b| b == b'_').count();
+    if underscores > 1 {
+        result.drain(..underscores - 1);
+    }
+    result.truncate(MAX_NAME_LEN);
+    result
+}
+
+/// Whether `value` can be used as the value of a label taken from an event at
+/// runtime, where there is no way to report an error: it must be non-empty,
+/// not exceed Loki's length limit and not contain control characters.
+pub fn is_valid_value(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_VALUE_LEN && !value.chars().any(char::is_control)
+}
+
+/// How the level of an event is represented, as a label or, in single-stream
+/// mode, as a field of the log line.
+#[derive(Clone)]
+pub struct LevelLabel {
+    pub name: String,
+    pub values: LevelMap<String>,
+    /// The event field whose value overrides the level's value.
+    pub field: Option<String>,
+}
+
+impl Default for LevelLabel {
+    fn default() -> LevelLabel {
+        LevelLabel {
+            name: "level".into(),
+            values: LevelMap::from_fn(|level| {
+                match level {
+                    Level::TRACE => "trace",
+                    Level::DEBUG => "debug",
+                    Level::INFO => "info",
+                    Level::WARN => "warn",
+                    Level::ERROR => "error",
+                }
+                .into()
+            }),
+            field: None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct FormattedLabels {
     seen_keys: HashSet<String>,
     formatted: String,
+    level: LevelLabel,
+    /// Whether to rewrite invalid labels instead of rejecting them.
+    sanitize: bool,
 }
 
 impl FormattedLabels {
@@ -16,28 +122,52 @@ impl FormattedLabels {
         FormattedLabels {
             seen_keys: HashSet::new(),
             formatted: String::from("{"),
+            level: LevelLabel::default(),
+            sanitize: false,
+        }
+    }
+    pub fn set_sanitize(&mut self, sanitize: bool) {
+        self.sanitize = sanitize;
+    }
+    fn check_key(&self, key: String) -> Result<String, Error> {
+        if self.sanitize {
+            Ok(sanitize_key(&key))
+        } else {
+            validate_key(key)
         }
     }
+    pub fn level_label(&self) -> &LevelLabel {
+        &self.level
+    }
+    pub fn set_level_name(&mut self, name: String) -> Result<(), Error> {
+        let name = self.check_key(name)?;
+        if self.seen_keys.contains(&name) {
+            return Err(Error(ErrorI::DuplicateLabel(name)));
+        }
+        self.level.name = name;
+        Ok(())
+    }
+    pub fn set_level_value(&mut self, level: Level, value: String) -> Result<(), Error> {
+        if !is_valid_value(&value) {
+            return Err(Error(ErrorI::InvalidLevelValue(value)));
+        }
+        self.level.values[level] = value;
+        Ok(())
+    }
+    pub fn set_level_field(&mut self, field: String) {
+        self.level.field = Some(field);
+    }
     pub fn add(&mut self, key: String, value: &str) -> Result<(), Error> {
-        // Couldn't find documentation except for the promtail source code:
-        // https://github.com/grafana/loki/blob/8c06c546ab15a568f255461f10318dae37e022d3/vendor/github.com/prometheus/prometheus/promql/parser/generated_parser.y#L597-L598
-        //
-        // Apparently labels that confirm to yacc's "IDENTIFIER" are okay. I
-        // couldn't find which those are. Let's be conservative and allow
-        // `[A-Za-z_]*`.
-        for (i, b) in key.bytes().enumerate() {
-            match b {
-                b'A'..=b'Z' | b'a'..=b'z' | b'_' => {}
-                // The first byte outside of the above range must start a UTF-8
-                // character.
-                _ => {
-                    let c = key[i..].chars().next().unwrap();
-                    return Err(Error(ErrorI::InvalidLabelCharacter(key, c)));
-                }
-            }
+        let key = self.check_key(key)?;
+        if key == self.level.name {
+            return Err(Error(ErrorI::ReservedLabelLevel(key)));
         }
-        if key == "level" {
-            return Err(Error(ErrorI::ReservedLabelLevel));
+        let mut value = value;
+        if value.len() > MAX_VALUE_LEN {
+            if !self.sanitize {
+                return Err(Error(ErrorI::LabelValueTooLong(key)));
+            }
+            value = &value[..floor_char_boundary(value, MAX_VALUE_LEN)];
         }
 
         // Couldn't find documentation except for the promtail source code:
@@ -55,18 +185,26 @@ impl FormattedLabels {
         }
         Ok(())
     }
-    pub fn finish(&self, level: Level) -> String {
+    pub fn is_empty(&self) -> bool {
+        self.seen_keys.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.seen_keys.len()
+    }
+    /// Format the labels without the level, for sending all levels in one
+    /// stream.
+    pub fn finish_without_level(&self) -> String {
+        let mut result = self.formatted.clone();
+        result.push('}');
+        result
+    }
+    /// Format the labels with `level_value` as the value of the level label.
+    pub fn finish(&self, level_value: &str) -> String {
         let mut result = self.formatted.clone();
         if result.len() > 1 {
             result.push(',');
         }
-        result.push_str(match level {
-            Level::TRACE => "level=\"trace\"}",
-            Level::DEBUG => "level=\"debug\"}",
-            Level::INFO => "level=\"info\"}",
-            Level::WARN => "level=\"warn\"}",
-            Level::ERROR => "level=\"error\"}",
-        });
+        write!(&mut result, "{}={:?}}}", self.level.name, level_value).unwrap();
         result
     }
 }
@@ -76,36 +214,129 @@ mod test {
     use super::FormattedLabels;
     use tracing_core::Level;
 
+    fn finish(labels: &FormattedLabels, level: Level) -> String {
+        labels.finish(&labels.level_label().values[level])
+    }
+
     #[test]
     fn simple() {
         assert_eq!(
-            FormattedLabels::new().finish(Level::TRACE),
+            finish(&FormattedLabels::new(), Level::TRACE),
             r#"{level="trace"}"#,
         );
         assert_eq!(
-            FormattedLabels::new().finish(Level::DEBUG),
+            finish(&FormattedLabels::new(), Level::DEBUG),
             r#"{level="debug"}"#,
         );
         assert_eq!(
-            FormattedLabels::new().finish(Level::INFO),
+            finish(&FormattedLabels::new(), Level::INFO),
             r#"{level="info"}"#,
         );
         assert_eq!(
-            FormattedLabels::new().finish(Level::WARN),
+            finish(&FormattedLabels::new(), Level::WARN),
             r#"{level="warn"}"#,
         );
         assert_eq!(
-            FormattedLabels::new().finish(Level::ERROR),
+            finish(&FormattedLabels::new(), Level::ERROR),
             r#"{level="error"}"#,
         );
     }
 
+    #[test]
+    fn without_level() {
+        let mut labels = FormattedLabels::new();
+        assert!(labels.is_empty());
+        labels.add("host".into(), "mine").unwrap();
+        labels.add("app".into(), "web").unwrap();
+        assert!(!labels.is_empty());
+        assert_eq!(labels.finish_without_level(), r#"{host="mine",app="web"}"#);
+    }
+
     #[test]
     fn level() {
         assert!(FormattedLabels::new().add("level".into(), "").is_err());
         assert!(FormattedLabels::new().add("level".into(), "blurb").is_err());
     }
 
+    #[test]
+    fn custom_level() {
+        let mut labels = FormattedLabels::new();
+        labels.add("host".into(), "mine").unwrap();
+        assert!(labels.clone().set_level_name("host".into()).is_err());
+        assert!(labels.clone().set_level_name("sev-erity".into()).is_err());
+        labels.set_level_name("severity".into()).unwrap();
+        labels
+            .set_level_value(Level::INFO, "informational".into())
+            .unwrap();
+        assert!(labels.set_level_value(Level::INFO, "".into()).is_err());
+        assert!(labels
+            .set_level_value(Level::INFO, "\u{1b}[31m".into())
+            .is_err());
+        assert!(labels
+            .set_level_value(Level::INFO, "x".repeat(2049))
+            .is_err());
+        assert_eq!(
+            finish(&labels, Level::INFO),
+            r#"{host="mine",severity="informational"}"#,
+        );
+        assert_eq!(
+            finish(&labels, Level::WARN),
+            r#"{host="mine",severity="warn"}"#,
+        );
+        assert_eq!(
+            labels.finish("critical"),
+            r#"{host="mine",severity="critical"}"#,
+        );
+        assert!(labels.clone().add("severity".into(), "").is_err());
+        labels.add("level".into(), "").unwrap();
+    }
+
+    #[test]
+    fn validation() {
+        let mut labels = FormattedLabels::new();
+        labels.add("k8s_node2".into(), "a").unwrap();
+        labels.add("_x".into(), "b").unwrap();
+        for invalid in ["", "2k", "node-name", "__name__", "naïve"] {
+            assert!(
+                labels.clone().add(invalid.into(), "").is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert!(labels.clone().add("n".repeat(1025), "").is_err());
+        labels.add("n".repeat(1024), "").unwrap();
+        assert!(labels
+            .clone()
+            .add("long".into(), &"v".repeat(2049))
+            .is_err());
+        labels.add("long".into(), &"v".repeat(2048)).unwrap();
+        assert_eq!(labels.len(), 4);
+    }
+
+    #[test]
+    fn sanitize() {
+        let mut labels = FormattedLabels::new();
+        labels.set_sanitize(true);
+        labels.add("node-name".into(), "a").unwrap();
+        labels.add("2k".into(), "b").unwrap();
+        labels.add("__name__".into(), "c").unwrap();
+        labels.add("".into(), "d").unwrap();
+        labels.add("naïve".into(), &"é".repeat(1025)).unwrap();
+        assert!(labels.clone().add("node.name".into(), "").is_err());
+        assert!(labels.clone().add("level".into(), "").is_err());
+        assert_eq!(
+            labels.finish_without_level(),
+            format!(
+                r#"{{node_name="a",_2k="b",_name__="c",_="d",na_ve="{}"}}"#,
+                "é".repeat(1024),
+            ),
+        );
+        labels.add("n".repeat(2000), "").unwrap();
+        assert!(labels
+            .finish_without_level()
+            .contains(&format!(",{}=", "n".repeat(1024))));
+    }
+
     #[test]
     fn duplicate() {
         let mut labels = FormattedLabels::new();
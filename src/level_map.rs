@@ -3,7 +3,7 @@ use std::ops;
 use std::slice;
 use tracing_core::Level;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct LevelMap<T> {
     map: [T; 5],
 }
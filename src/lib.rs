@@ -53,9 +53,16 @@ compile_error!(
 /// Use this to avoid depending on a potentially-incompatible `url` version yourself.
 pub extern crate url;
 
-use loki_api::logproto as loki;
-use loki_api::prost;
-use serde::Serialize;
+/// The re-exported `serde_json` dependency of this crate.
+///
+/// Use this to avoid depending on a potentially-incompatible `serde_json` version yourself.
+pub extern crate serde_json;
+
+/// The re-exported `reqwest` dependency of this crate.
+///
+/// Use this to avoid depending on a potentially-incompatible `reqwest` version yourself.
+pub extern crate reqwest;
+
 use std::cmp;
 use std::collections::HashMap;
 use std::error;
@@ -63,14 +70,15 @@ use std::fmt;
 use std::future::Future;
 use std::mem;
 use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use std::time::Duration;
 use std::time::SystemTime;
 use tokio::sync::mpsc;
 use tracing::instrument::WithSubscriber;
-use tracing_core::field::Field;
-use tracing_core::field::Visit;
 use tracing_core::span::Attributes;
 use tracing_core::span::Id;
 use tracing_core::span::Record;
@@ -82,28 +90,90 @@ use tracing_subscriber::layer::Context as TracingContext;
 use tracing_subscriber::registry::LookupSpan;
 use url::Url;
 
+use auth::Auth;
+use dedup::Dedup;
+use encode::Buffer;
+use event::CapturedEvent;
+use event::EventFormatter;
+use event::EventMeta;
+use event::FieldList;
+use event::FieldRecorder;
+use event::FieldValue;
+use event::SpanContext;
+use extra_fields::ExtraFields;
+use field_filter::FieldFilter;
 use labels::FormattedLabels;
 use level_map::LevelMap;
-use log_support::SerializeEventFieldMapStrippingLog;
+use line_limits::LineLimits;
+use monotonic::make_monotonic;
 use no_subscriber::NoSubscriber;
+use push::ConnectionOptions;
+use push::PushClient;
+use rate_limit::RateLimiter;
+use rate_limit::RateLimits;
+use rate_limit::TokenBucket;
 use ErrorInner as ErrorI;
 
+pub use auth::BearerToken;
+pub use build_info::BuildInfo;
 pub use builder::builder;
 pub use builder::Builder;
+pub use clock::Clock;
+pub use clock::SystemClock;
+pub use config::BatchingConfig;
+pub use config::Config;
+pub use config::FormatConfig;
+pub use config::HttpConfig;
+pub use config::RetryConfig;
+pub use config::TimestampFieldConfig;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub use config::TlsConfig;
+pub use kubernetes::Kubernetes;
+pub use line_limits::LineOverflow;
+pub use oauth2::OAuth2;
+pub use resource::Resource;
+pub use resource::ResourceTarget;
+pub use timestamp::TimestampFormat;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub use tls::TlsVersion;
 
+mod auth;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod build_info;
 mod builder;
+mod clock;
+mod config;
+mod dedup;
+mod encode;
+mod env_config;
+mod event;
+mod extra_fields;
+mod field_filter;
+mod kubernetes;
 mod labels;
 mod level_map;
-mod log_support;
+mod line_limits;
+mod monotonic;
 mod no_subscriber;
+mod oauth2;
+mod push;
+mod rate_limit;
+mod resource;
+#[cfg(test)]
+mod test_server;
+mod timestamp;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+mod tls;
 
 #[cfg(doctest)]
 #[doc = include_str!("../README.md")]
 struct ReadmeDoctests;
 
 fn event_channel() -> (
-    mpsc::Sender<Option<LokiEvent>>,
-    mpsc::Receiver<Option<LokiEvent>>,
+    mpsc::Sender<Option<CapturedEvent>>,
+    mpsc::Receiver<Option<CapturedEvent>>,
 ) {
     mpsc::channel(512)
 }
@@ -131,11 +201,34 @@ enum ErrorInner {
     DuplicateExtraField(String),
     DuplicateHttpHeader(String),
     DuplicateLabel(String),
+    Config(String, Box<ErrorInner>),
+    EmptyLabelName,
+    HttpClient(String),
+    HttpClientOptions,
+    Env(String, Box<ErrorInner>),
     InvalidHttpHeaderName(String),
     InvalidHttpHeaderValue(String),
     InvalidLabelCharacter(String, char),
+    InvalidLevelValue(String),
     InvalidLokiUrl,
-    ReservedLabelLevel,
+    InvalidRateLimit(f64, f64),
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    InvalidTlsCertificate(String),
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    InvalidTlsIdentity(String),
+    LabelNameTooLong(String),
+    LabelValueTooLong(String),
+    MalformedEnvEntry(String),
+    MissingLokiUrl,
+    NoLabels,
+    NotUnicode,
+    ReservedLabelLevel(String),
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    ReadFile(String, String),
+    ReservedLabelName(String),
+    TooManyLabels(usize),
+    UndetectedResource(&'static str),
+    UnknownLevel(String),
 }
 
 impl fmt::Display for ErrorInner {
@@ -145,13 +238,56 @@ impl fmt::Display for ErrorInner {
             DuplicateExtraField(key) => write!(f, "duplicate extra field key {:?}", key),
             DuplicateHttpHeader(name) => write!(f, "duplicate HTTP header {:?}", name),
             DuplicateLabel(key) => write!(f, "duplicate label key {:?}", key),
+            Config(path, inner) => write!(f, "config {}: {}", path, inner),
+            EmptyLabelName => write!(f, "empty label key"),
+            HttpClient(e) => write!(f, "couldn't build the HTTP client: {}", e),
+            HttpClientOptions => write!(
+                f,
+                "TLS and connection options don't apply to a supplied HTTP client",
+            ),
+            Env(name, inner) => write!(f, "environment variable {}: {}", name, inner),
             InvalidHttpHeaderName(name) => write!(f, "invalid HTTP header name {:?}", name),
             InvalidHttpHeaderValue(name) => write!(f, "invalid HTTP header value for {:?}", name),
             InvalidLabelCharacter(key, c) => {
                 write!(f, "invalid label character {:?} in key {:?}", c, key)
             }
+            InvalidLevelValue(value) => write!(
+                f,
+                "invalid level label value {:?}, it must be non-empty, \
+                 at most 2048 bytes long and without control characters",
+                value,
+            ),
             InvalidLokiUrl => write!(f, "invalid Loki URL"),
-            ReservedLabelLevel => write!(f, "cannot add custom label for \"level\""),
+            InvalidRateLimit(per_second, burst) => write!(
+                f,
+                "invalid rate limit of {} events per second with bursts of {}, \
+                 both must be positive",
+                per_second, burst,
+            ),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            InvalidTlsCertificate(e) => write!(f, "invalid TLS certificate: {}", e),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            InvalidTlsIdentity(e) => write!(f, "invalid TLS client identity: {}", e),
+            LabelNameTooLong(key) => write!(f, "label key {:?} is too long", key),
+            LabelValueTooLong(key) => write!(f, "value of label {:?} is too long", key),
+            MalformedEnvEntry(entry) => write!(f, "expected `key=value`, got {:?}", entry),
+            MissingLokiUrl => write!(f, "no Loki URL set"),
+            NoLabels => write!(f, "at least one label is required in single-stream mode"),
+            NotUnicode => write!(f, "not valid unicode"),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            ReadFile(path, e) => write!(f, "couldn't read {:?}: {}", path, e),
+            ReservedLabelLevel(key) => write!(f, "cannot add custom label for {:?}", key),
+            ReservedLabelName(key) => {
+                write!(f, "label key {:?} is reserved, it starts with \"__\"", key)
+            }
+            TooManyLabels(count) => write!(
+                f,
+                "{} labels exceed Loki's limit of {} labels per stream",
+                count,
+                labels::MAX_LABELS,
+            ),
+            UndetectedResource(key) => write!(f, "couldn't detect resource {:?}", key),
+            UnknownLevel(level) => write!(f, "unknown level {:?}", level),
         }
     }
 }
@@ -227,67 +363,74 @@ pub fn layer(
 ///
 /// See the crate's root documentation for an example.
 pub struct Layer {
-    extra_fields: HashMap<String, String>,
-    sender: mpsc::Sender<Option<LokiEvent>>,
+    extra_fields: ExtraFields,
+    field_filter: FieldFilter,
+    max_field_size: Option<usize>,
+    rate_limiter: Arc<RateLimiter>,
+    clock: Arc<dyn Clock>,
+    sender: mpsc::Sender<Option<CapturedEvent>>,
 }
 
 struct LokiEvent {
     trigger_send: bool,
     timestamp: SystemTime,
     level: Level,
+    /// The value of the level label if overridden by an event field.
+    level_value: Option<String>,
     message: String,
 }
 
-#[derive(Serialize)]
-struct SerializedEvent<'a> {
-    #[serde(flatten)]
-    event: SerializeEventFieldMapStrippingLog<'a>,
-    #[serde(flatten)]
-    extra_fields: &'a HashMap<String, String>,
-    #[serde(flatten)]
-    span_fields: serde_json::Map<String, serde_json::Value>,
-    _spans: &'a [&'a str],
-    _target: &'a str,
-    _module_path: Option<&'a str>,
-    _file: Option<&'a str>,
-    _line: Option<u32>,
+impl Layer {
+    fn send(&self, event: CapturedEvent) {
+        // TODO: Anything useful to do when the capacity has been reached?
+        let _ = self.sender.try_send(Some(event));
+    }
 }
 
-#[derive(Default)]
-struct Fields {
-    fields: serde_json::Map<String, serde_json::Value>,
+/// An event reporting that `suppressed` events from the callsite of `meta`
+/// were dropped by rate limiting.
+fn suppressed_event(
+    meta: &EventMeta,
+    timestamp: SystemTime,
+    suppressed: u64,
+    dynamic_fields: Vec<serde_json::Value>,
+) -> CapturedEvent {
+    let callsite = match (meta.file(), meta.line()) {
+        (Some(file), Some(line)) => format!("{} ({}:{})", meta.target(), file, line),
+        _ => meta.target().to_owned(),
+    };
+    let mut fields = FieldList::new();
+    fields.push(
+        "message",
+        FieldValue::Str(format!(
+            "suppressed {} events from {}",
+            suppressed, callsite
+        )),
+    );
+    fields.push("suppressed", FieldValue::U64(suppressed));
+    CapturedEvent {
+        timestamp,
+        meta: meta.clone(),
+        fields,
+        dynamic_fields,
+        spans: None,
+    }
 }
 
-impl Fields {
-    fn record_impl(&mut self, field: &Field, value: serde_json::Value) {
-        self.fields.insert(field.name().into(), value);
-    }
-    fn record<T: Into<serde_json::Value>>(&mut self, field: &Field, value: T) {
-        self.record_impl(field, value.into());
-    }
+struct SpanFields {
+    fields: FieldList,
+    /// Cached context for events inside of this span, reset when its fields
+    /// change.
+    context: Option<Arc<SpanContext>>,
 }
 
-impl Visit for Fields {
-    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
-        self.record(field, format!("{:?}", value));
-    }
-    fn record_f64(&mut self, field: &Field, value: f64) {
-        self.record(field, value);
-    }
-    fn record_i64(&mut self, field: &Field, value: i64) {
-        self.record(field, value);
-    }
-    fn record_u64(&mut self, field: &Field, value: u64) {
-        self.record(field, value);
-    }
-    fn record_bool(&mut self, field: &Field, value: bool) {
-        self.record(field, value);
-    }
-    fn record_str(&mut self, field: &Field, value: &str) {
-        self.record(field, value);
-    }
-    fn record_error(&mut self, field: &Field, value: &(dyn error::Error + 'static)) {
-        self.record(field, format!("{}", value));
+impl SpanFields {
+    /// The cached context, if it's still valid for the `parent` context.
+    fn cached_context(&self, parent: Option<&Arc<SpanContext>>) -> Option<Arc<SpanContext>> {
+        self.context
+            .as_ref()
+            .filter(|context| context.is_child_of(parent))
+            .cloned()
     }
 }
 
@@ -295,81 +438,196 @@ impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for La
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: TracingContext<'_, S>) {
         let span = ctx.span(id).expect("Span not found, this is a bug");
         let mut extensions = span.extensions_mut();
-        if extensions.get_mut::<Fields>().is_none() {
-            let mut fields = Fields::default();
-            attrs.record(&mut fields);
-            extensions.insert(fields);
+        if extensions.get_mut::<SpanFields>().is_none() {
+            let mut fields = FieldList::new();
+            attrs.record(&mut FieldRecorder {
+                fields: &mut fields,
+                target: attrs.metadata().target(),
+                filter: &self.field_filter,
+                max_field_size: self.max_field_size,
+                event: false,
+            });
+            extensions.insert(SpanFields {
+                fields,
+                context: None,
+            });
         }
     }
     fn on_record(&self, id: &Id, values: &Record<'_>, ctx: TracingContext<'_, S>) {
         let span = ctx.span(id).expect("Span not found, this is a bug");
         let mut extensions = span.extensions_mut();
-        let fields = extensions.get_mut::<Fields>().expect("unregistered span");
-        values.record(fields);
+        let fields = extensions
+            .get_mut::<SpanFields>()
+            .expect("unregistered span");
+        fields.context = None;
+        values.record(&mut FieldRecorder {
+            fields: &mut fields.fields,
+            target: span.metadata().target(),
+            filter: &self.field_filter,
+            max_field_size: self.max_field_size,
+            event: false,
+        });
     }
     fn on_event(&self, event: &Event<'_>, ctx: TracingContext<'_, S>) {
-        let timestamp = SystemTime::now();
+        let timestamp = self.clock.now();
         let normalized_meta = event.normalized_metadata();
-        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
-        let mut span_fields: serde_json::Map<String, serde_json::Value> = Default::default();
+        let meta = match &normalized_meta {
+            Some(meta) => EventMeta::from_log(meta),
+            None => EventMeta::Tracing(event.metadata()),
+        };
+        if self.rate_limiter.is_enabled() {
+            let decision = self.rate_limiter.check(&meta, timestamp);
+            if let Some(suppressed) = decision.summary {
+                self.send(suppressed_event(
+                    &meta,
+                    timestamp,
+                    suppressed,
+                    self.extra_fields.capture_dynamic(),
+                ));
+            }
+            if !decision.keep {
+                return;
+            }
+        }
+        let mut fields = FieldList::new();
+        event.record(&mut FieldRecorder {
+            fields: &mut fields,
+            target: meta.target(),
+            filter: &self.field_filter,
+            max_field_size: self.max_field_size,
+            event: true,
+        });
         let spans = event
             .parent()
             .cloned()
             .or_else(|| ctx.current_span().id().cloned())
             .and_then(|id| {
-                ctx.span_scope(&id).map(|scope| {
-                    scope.from_root().fold(Vec::new(), |mut spans, span| {
-                        span_fields.extend(
-                            span.extensions()
-                                .get::<Fields>()
-                                .expect("unregistered span")
-                                .fields
-                                .iter()
-                                .map(|(f, v)| (f.clone(), v.clone())),
-                        );
-                        spans.push(span.name());
-                        spans
+                ctx.span_scope(&id).and_then(|scope| {
+                    scope.from_root().fold(None, |parent, span| {
+                        // Only take the write lock if the cached context needs
+                        // to be (re)built, so that threads logging in the same
+                        // spans don't contend.
+                        let extensions = span.extensions();
+                        let fields = extensions.get::<SpanFields>().expect("unregistered span");
+                        if let Some(context) = fields.cached_context(parent.as_ref()) {
+                            return Some(context);
+                        }
+                        drop(extensions);
+                        let mut extensions = span.extensions_mut();
+                        let fields = extensions
+                            .get_mut::<SpanFields>()
+                            .expect("unregistered span");
+                        // Another thread might have built it in the meantime.
+                        if let Some(context) = fields.cached_context(parent.as_ref()) {
+                            return Some(context);
+                        }
+                        let context =
+                            Arc::new(SpanContext::new(parent, span.name(), &fields.fields));
+                        fields.context = Some(context.clone());
+                        Some(context)
                     })
                 })
-            })
-            .unwrap_or(Vec::new());
-        // TODO: Anything useful to do when the capacity has been reached?
-        let _ = self.sender.try_send(Some(LokiEvent {
-            trigger_send: !meta.target().starts_with("tracing_loki"),
+            });
+        self.send(CapturedEvent {
             timestamp,
-            level: *meta.level(),
-            message: serde_json::to_string(&SerializedEvent {
-                event: SerializeEventFieldMapStrippingLog(event),
-                extra_fields: &self.extra_fields,
-                span_fields,
-                _spans: &spans,
-                _target: meta.target(),
-                _module_path: meta.module_path(),
-                _file: meta.file(),
-                _line: meta.line(),
-            })
-            .expect("json serialization shouldn't fail"),
-        }));
+            meta,
+            fields,
+            dynamic_fields: self.extra_fields.capture_dynamic(),
+            spans,
+        });
+    }
+}
+
+/// Options of the [`BackgroundTask`] that don't need to be known until it is
+/// constructed.
+#[derive(Clone)]
+struct TaskOptions {
+    dedup_window: Option<Duration>,
+    max_concurrent_requests: usize,
+    monotonic_timestamps: bool,
+    single_stream: bool,
+    clock: Arc<dyn Clock>,
+    retry: RetryOptions,
+    auth: Option<Arc<Auth>>,
+    http_client: Option<reqwest::Client>,
+    connection: ConnectionOptions,
+    request_timeout: Option<Duration>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    tls: tls::TlsOptions,
+}
+
+#[derive(Clone, Copy)]
+struct RetryOptions {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    /// Outstanding entries are dropped once the backoff reaches this.
+    drop_after: Duration,
+}
+
+impl Default for TaskOptions {
+    fn default() -> TaskOptions {
+        TaskOptions {
+            dedup_window: None,
+            max_concurrent_requests: 1,
+            monotonic_timestamps: false,
+            single_stream: false,
+            clock: Arc::new(SystemClock),
+            retry: RetryOptions {
+                initial_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(600),
+                drop_after: Duration::from_secs(30),
+            },
+            auth: None,
+            http_client: None,
+            connection: ConnectionOptions::default(),
+            request_timeout: None,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            tls: tls::TlsOptions::default(),
+        }
     }
 }
 
 struct SendQueue {
     encoded_labels: String,
+    dedup: Option<Dedup>,
+    /// The ID of the request `sending` is part of.
+    in_flight: Option<u64>,
+    monotonic_timestamps: bool,
+    /// The timestamp of the last entry Loki accepted.
+    last_sent: Option<SystemTime>,
     sending: Vec<LokiEvent>,
     to_send: Vec<LokiEvent>,
 }
 
 impl SendQueue {
-    fn new(encoded_labels: String) -> SendQueue {
+    fn new(encoded_labels: String, options: &TaskOptions) -> SendQueue {
         SendQueue {
             encoded_labels,
+            dedup: options.dedup_window.map(Dedup::new),
+            in_flight: None,
+            monotonic_timestamps: options.monotonic_timestamps,
+            last_sent: None,
             sending: Vec::new(),
             to_send: Vec::new(),
         }
     }
     fn push(&mut self, event: LokiEvent) {
         // TODO: Add limit.
-        self.to_send.push(event);
+        match &mut self.dedup {
+            Some(dedup) => dedup.push(event, &mut self.to_send),
+            None => self.to_send.push(event),
+        }
+    }
+    fn dedup_deadline(&self) -> Option<SystemTime> {
+        self.dedup.as_ref().and_then(|d| d.deadline())
+    }
+    fn flush_repeated(&mut self, now: Option<SystemTime>) {
+        if let Some(dedup) = &mut self.dedup {
+            match now {
+                Some(now) => dedup.flush_expired(now, &mut self.to_send),
+                None => dedup.flush(&mut self.to_send),
+            }
+        }
     }
     fn drop_outstanding(&mut self) -> usize {
         let len = self.sending.len();
@@ -377,129 +635,244 @@ impl SendQueue {
         len
     }
     fn on_send_result(&mut self, result: Result<(), ()>) {
+        self.in_flight = None;
         match result {
-            Ok(()) => self.sending.clear(),
+            Ok(()) => {
+                if let Some(last) = self.sending.last() {
+                    self.last_sent = Some(last.timestamp);
+                }
+                self.sending.clear();
+            }
             Err(()) => {
                 self.sending.append(&mut self.to_send);
                 mem::swap(&mut self.sending, &mut self.to_send);
             }
         }
     }
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_none()
+    }
     fn should_send(&self) -> bool {
-        self.to_send.iter().any(|e| e.trigger_send)
+        self.is_idle() && self.to_send.iter().any(|e| e.trigger_send)
     }
-    fn prepare_sending(&mut self) -> loki::StreamAdapter {
-        if !self.sending.is_empty() {
+    /// Move the queued events into a new request with the given ID, returns
+    /// the number of timestamps adjusted to keep them increasing.
+    fn prepare_sending(&mut self, request_id: u64) -> usize {
+        if !self.is_idle() {
             panic!("can only prepare sending while no request is in flight");
         }
+        if self.to_send.is_empty() {
+            return 0;
+        }
         mem::swap(&mut self.sending, &mut self.to_send);
-        loki::StreamAdapter {
-            labels: self.encoded_labels.clone(),
-            entries: self
-                .sending
-                .iter()
-                .map(|e| loki::EntryAdapter {
-                    timestamp: Some(e.timestamp.into()),
-                    line: e.message.clone(),
-                })
-                .collect(),
-            // Couldn't find documentation except for the promtail source code:
-            // https://github.com/grafana/loki/blob/8c06c546ab15a568f255461f10318dae37e022d3/clients/pkg/promtail/client/batch.go#L55-L58
-            //
-            // In the Go code, the hash value isn't initialized explicitly,
-            // hence it is set to 0.
-            hash: 0,
+        self.in_flight = Some(request_id);
+        if self.monotonic_timestamps {
+            make_monotonic(&mut self.sending, self.last_sent)
+        } else {
+            0
         }
     }
+    fn sending(&self) -> (&str, impl Iterator<Item = (SystemTime, &str)> + Clone + '_) {
+        (
+            &self.encoded_labels,
+            self.sending.iter().map(|e| (e.timestamp, &e.message[..])),
+        )
+    }
 }
 
-#[derive(Debug)]
-struct BadRedirect {
-    status: u16,
-    to: Url,
+/// The send queues of all streams.
+struct Streams {
+    labels: FormattedLabels,
+    options: TaskOptions,
+    queues: Vec<SendQueue>,
+    /// The index of the queue for each value of the level label.
+    by_level_value: HashMap<String, usize>,
+    /// The index of the queue for each level, unless it's overridden.
+    by_level: LevelMap<usize>,
+    /// The maximum size of `by_level_value`.
+    max_level_values: usize,
 }
 
-impl fmt::Display for BadRedirect {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Following such a redirect drops the request body, and will likely
-        // give an HTTP 200 response even though nobody ever looked at the POST
-        // body.
-        //
-        // This can e.g. happen for login redirects when you post to a
-        // login-protected URL.
-        write!(f, "invalid HTTP {} redirect to {}", self.status, self.to)
+/// The maximum number of streams for level label values not configured for
+/// any level, see [`Builder::level_field`].
+const MAX_LEVEL_OVERRIDES: usize = 16;
+
+impl Streams {
+    fn index_of(&mut self, level_value: &str) -> usize {
+        if let Some(&i) = self.by_level_value.get(level_value) {
+            return i;
+        }
+        self.add(level_value)
+    }
+    fn add(&mut self, level_value: &str) -> usize {
+        let labels = self.labels.finish(level_value);
+        self.queues.push(SendQueue::new(labels, &self.options));
+        let i = self.queues.len() - 1;
+        self.by_level_value.insert(level_value.into(), i);
+        i
+    }
+    fn push(&mut self, event: LokiEvent) {
+        let i = match &event.level_value {
+            Some(value) => match self.by_level_value.get(&value[..]) {
+                Some(&i) => i,
+                // Don't let invalid or an unbounded number of values create
+                // streams, use the event's level instead.
+                None if labels::is_valid_value(value)
+                    && self.by_level_value.len() < self.max_level_values =>
+                {
+                    self.add(value)
+                }
+                None => self.by_level[event.level],
+            },
+            None => self.by_level[event.level],
+        };
+        self.queues[i].push(event);
     }
 }
 
-impl error::Error for BadRedirect {}
-
 /// The background task that ships logs to Loki. It must be [`tokio::spawn`]ed
 /// by the calling application.
 ///
 /// See the crate's root documentation for an example.
 pub struct BackgroundTask {
-    loki_url: Url,
-    receiver: mpsc::Receiver<Option<LokiEvent>>,
-    queues: LevelMap<SendQueue>,
+    receiver: mpsc::Receiver<Option<CapturedEvent>>,
+    formatter: EventFormatter,
+    streams: Streams,
     buffer: Buffer,
-    http_client: reqwest::Client,
+    push_client: Arc<PushClient>,
     backoff_count: u32,
-    backoff: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// The ID of the first request started after the last backoff.
+    backoff_from: u64,
+    retry: RetryOptions,
+    clock: Arc<dyn Clock>,
+    backoff: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    dedup_flush: Option<(SystemTime, Option<Pin<Box<dyn Future<Output = ()> + Send>>>)>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Fires when events suppressed by rate limiting should be reported.
+    summary_timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
     quitting: bool,
-    send_task:
-        Option<Pin<Box<dyn Future<Output = Result<(), Box<dyn error::Error>>> + Send + 'static>>>,
+    max_concurrent_requests: usize,
+    next_request_id: u64,
+    send_tasks: Vec<SendTask>,
+    /// The number of timestamps adjusted to keep them increasing, shared
+    /// with the [`BackgroundTaskController`].
+    adjusted_timestamps: Arc<AtomicU64>,
+}
+
+struct SendTask {
+    id: u64,
+    future: Pin<
+        Box<dyn Future<Output = Result<(), Box<dyn error::Error + Send + Sync>>> + Send + 'static>,
+    >,
 }
 
 impl BackgroundTask {
     fn new(
         loki_url: Url,
         http_headers: reqwest::header::HeaderMap,
-        receiver: mpsc::Receiver<Option<LokiEvent>>,
+        receiver: mpsc::Receiver<Option<CapturedEvent>>,
+        formatter: EventFormatter,
         labels: &FormattedLabels,
+        rate_limiter: Arc<RateLimiter>,
+        options: TaskOptions,
     ) -> Result<BackgroundTask, Error> {
-        Ok(BackgroundTask {
-            receiver,
-            loki_url: loki_url
+        if options.single_stream && labels.is_empty() {
+            return Err(Error(ErrorI::NoLabels));
+        }
+        let num_labels = labels.len() + usize::from(!options.single_stream);
+        if num_labels > labels::MAX_LABELS {
+            return Err(Error(ErrorI::TooManyLabels(num_labels)));
+        }
+        let mut streams = Streams {
+            labels: labels.clone(),
+            options: options.clone(),
+            queues: Vec::new(),
+            by_level_value: HashMap::new(),
+            by_level: LevelMap::default(),
+            max_level_values: 0,
+        };
+        if options.single_stream {
+            streams
+                .queues
+                .push(SendQueue::new(labels.finish_without_level(), &options));
+        } else {
+            let by_level =
+                LevelMap::from_fn(|level| streams.index_of(&labels.level_label().values[level]));
+            streams.by_level = by_level;
+            let overrides = if labels.level_label().field.is_some() {
+                MAX_LEVEL_OVERRIDES
+            } else {
+                0
+            };
+            streams.max_level_values = streams.by_level_value.len() + overrides;
+        }
+        // Each stream has at most one request in flight, more requests could
+        // never be sent concurrently.
+        let max_streams = cmp::max(streams.queues.len(), streams.max_level_values);
+        let max_concurrent_requests = options.max_concurrent_requests.clamp(1, max_streams);
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        let tls_default = options.tls.is_default();
+        #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+        let tls_default = true;
+        let client = match options.http_client {
+            Some(_) if !tls_default || options.connection != ConnectionOptions::default() => {
+                return Err(Error(ErrorI::HttpClientOptions));
+            }
+            Some(client) => client,
+            None => {
+                let client_builder = PushClient::default_client(&options.connection);
+                #[cfg(any(feature = "native-tls", feature = "rustls"))]
+                let client_builder = options.tls.apply(client_builder);
+                client_builder
+                    .build()
+                    .map_err(|e| Error(ErrorI::HttpClient(e.to_string())))?
+            }
+        };
+        let mut headers = PushClient::default_headers();
+        headers.extend(http_headers);
+        let push_client = PushClient {
+            client,
+            url: loki_url
                 .join("loki/api/v1/push")
                 .map_err(|_| Error(ErrorI::InvalidLokiUrl))?,
-            queues: LevelMap::from_fn(|level| SendQueue::new(labels.finish(level))),
+            headers,
+            auth: options.auth,
+            timeout: options.request_timeout,
+        };
+        Ok(BackgroundTask {
+            receiver,
+            formatter,
+            streams,
             buffer: Buffer::new(),
-            http_client: reqwest::Client::builder()
-                .user_agent(concat!(
-                    env!("CARGO_PKG_NAME"),
-                    "/",
-                    env!("CARGO_PKG_VERSION")
-                ))
-                .default_headers(http_headers)
-                .redirect(reqwest::redirect::Policy::custom(|a| {
-                    let status = a.status().as_u16();
-                    if status == 302 || status == 303 {
-                        let to = a.url().clone();
-                        return a.error(BadRedirect { status, to });
-                    }
-                    reqwest::redirect::Policy::default().redirect(a)
-                }))
-                .build()
-                .expect("reqwest client builder"),
+            push_client: Arc::new(push_client),
             backoff_count: 0,
+            backoff_from: 0,
+            retry: options.retry,
+            clock: options.clock,
             backoff: None,
+            dedup_flush: None,
+            rate_limiter,
+            summary_timer: None,
             quitting: false,
-            send_task: None,
+            max_concurrent_requests,
+            next_request_id: 0,
+            send_tasks: Vec::new(),
+            adjusted_timestamps: Arc::new(AtomicU64::new(0)),
         })
     }
-    fn backoff_time(&self) -> (bool, Duration) {
-        let backoff_time = if self.backoff_count >= 1 {
-            Duration::from_millis(
-                500u64
-                    .checked_shl(self.backoff_count - 1)
-                    .unwrap_or(u64::MAX),
-            )
+    /// Whether to drop the outstanding entries, and how long to back off,
+    /// after `backoff_count` consecutive failures.
+    fn backoff_time(&self, backoff_count: u32) -> (bool, Duration) {
+        let backoff_time = if backoff_count >= 1 {
+            1u32.checked_shl(backoff_count - 1)
+                .and_then(|factor| self.retry.initial_backoff.checked_mul(factor))
+                .unwrap_or(Duration::MAX)
         } else {
             Duration::from_millis(0)
         };
         (
-            backoff_time >= Duration::from_secs(30),
-            cmp::min(backoff_time, Duration::from_secs(600)),
+            backoff_time >= self.retry.drop_after,
+            cmp::min(backoff_time, self.retry.max_backoff),
         )
     }
 }
@@ -511,12 +884,86 @@ impl Future for BackgroundTask {
 
         while let Poll::Ready(maybe_maybe_item) = Pin::new(&mut self.receiver).poll_recv(cx) {
             match maybe_maybe_item {
-                Some(Some(item)) => self.queues[item.level].push(item),
+                Some(Some(item)) => {
+                    let BackgroundTask {
+                        formatter, streams, ..
+                    } = &mut *self;
+                    formatter.format(item, |event| streams.push(event));
+                }
                 Some(None) => self.quitting = true, // Explicit close.
                 None => self.quitting = true,       // The sender was dropped.
             }
         }
 
+        let now = self.clock.now();
+        // Report events suppressed by rate limiting even if their callsite
+        // went quiet.
+        if let Some(interval) = self.rate_limiter.summary_interval() {
+            // Report all suppressed events when quitting.
+            let due = self.quitting || {
+                let clock = self.clock.clone();
+                let timer = self
+                    .summary_timer
+                    .get_or_insert_with(|| clock.sleep(interval));
+                let fired = timer.as_mut().poll(cx).is_ready();
+                if fired {
+                    // Poll again to start the next timer.
+                    self.summary_timer = None;
+                    cx.waker().wake_by_ref();
+                }
+                fired
+            };
+            if due {
+                let BackgroundTask {
+                    formatter,
+                    streams,
+                    rate_limiter,
+                    quitting,
+                    ..
+                } = &mut *self;
+                for (meta, suppressed) in rate_limiter.take_due_summaries(now, *quitting) {
+                    let dynamic_fields = formatter.extra_fields.capture_dynamic();
+                    let event = suppressed_event(&meta, now, suppressed, dynamic_fields);
+                    formatter.format(event, |event| streams.push(event));
+                }
+            }
+        }
+        // Flush all coalesced repetitions when quitting.
+        let flush_until = Some(now).filter(|_| !self.quitting);
+        for q in self.streams.queues.iter_mut() {
+            q.flush_repeated(flush_until);
+        }
+        match self
+            .streams
+            .queues
+            .iter()
+            .filter_map(|q| q.dedup_deadline())
+            .min()
+        {
+            Some(deadline) => {
+                if self.dedup_flush.as_ref().map(|&(d, _)| d) != Some(deadline) {
+                    let remaining = deadline.duration_since(now).unwrap_or_default();
+                    self.dedup_flush = Some((deadline, Some(self.clock.sleep(remaining))));
+                }
+                // The timer is dropped once it fired, it must not be polled
+                // again.
+                let (_, timer) = self.dedup_flush.as_mut().unwrap();
+                if let Some(sleep) = timer {
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        *timer = None;
+                        // The timer might fire before the clock reached the
+                        // deadline, e.g. if it was adjusted. Start a new one
+                        // on the next poll, unless the run is flushed then.
+                        if now < deadline {
+                            self.dedup_flush = None;
+                        }
+                        cx.waker().wake_by_ref();
+                    }
+                }
+            }
+            None => self.dedup_flush = None,
+        }
+
         let mut backing_off = if let Some(backoff) = &mut self.backoff {
             matches!(Pin::new(backoff).poll(cx), Poll::Pending)
         } else {
@@ -526,78 +973,140 @@ impl Future for BackgroundTask {
             self.backoff = None;
         }
         loop {
-            if let Some(send_task) = &mut self.send_task {
-                match Pin::new(send_task).poll(cx) {
-                    Poll::Ready(res) => {
-                        if let Err(e) = &res {
-                            let (drop_outstanding, backoff_time) = self.backoff_time();
+            let mut i = 0;
+            while i < self.send_tasks.len() {
+                let res = match self.send_tasks[i].future.as_mut().poll(cx) {
+                    Poll::Ready(res) => res,
+                    Poll::Pending => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let id = self.send_tasks.swap_remove(i).id;
+                match &res {
+                    Err(e) if push::is_final(&**e) => {
+                        // Retrying might send the entries twice, drop them.
+                        let num_dropped: usize = self
+                            .streams
+                            .queues
+                            .iter_mut()
+                            .filter(|q| q.in_flight == Some(id))
+                            .map(|q| q.drop_outstanding())
+                            .sum();
+                        drop(default_guard);
+                        tracing::error!(
+                            num_dropped,
+                            error = %e,
+                            "couldn't send logs to loki, not retrying",
+                        );
+                        default_guard = tracing::subscriber::set_default(NoSubscriber::default());
+                    }
+                    Err(e) => {
+                        // Requests that were already in flight when the
+                        // last backoff started likely failed for the same
+                        // reason, only back off further for newer ones.
+                        let escalate = id >= self.backoff_from;
+                        let backoff_count = if escalate {
+                            self.backoff_count
+                        } else {
+                            self.backoff_count.saturating_sub(1)
+                        };
+                        let (drop_outstanding, backoff_time) = self.backoff_time(backoff_count);
+                        drop(default_guard);
+                        tracing::error!(
+                            error_count = backoff_count + 1,
+                            ?backoff_time,
+                            error = %e,
+                            "couldn't send logs to loki",
+                        );
+                        default_guard = tracing::subscriber::set_default(NoSubscriber::default());
+                        if drop_outstanding {
+                            let num_dropped: usize = self
+                                .streams
+                                .queues
+                                .iter_mut()
+                                .filter(|q| q.in_flight == Some(id))
+                                .map(|q| q.drop_outstanding())
+                                .sum();
                             drop(default_guard);
                             tracing::error!(
-                                error_count = self.backoff_count + 1,
-                                ?backoff_time,
-                                error = %e,
-                                "couldn't send logs to loki",
+                                num_dropped,
+                                "dropped outstanding messages due to sending errors",
                             );
                             default_guard =
                                 tracing::subscriber::set_default(NoSubscriber::default());
-                            if drop_outstanding {
-                                let num_dropped: usize =
-                                    self.queues.values_mut().map(|q| q.drop_outstanding()).sum();
-                                drop(default_guard);
-                                tracing::error!(
-                                    num_dropped,
-                                    "dropped outstanding messages due to sending errors",
-                                );
-                                default_guard =
-                                    tracing::subscriber::set_default(NoSubscriber::default());
+                        }
+                        if escalate {
+                            let mut backoff = self.clock.sleep(backoff_time);
+                            // Register for wakeup, nothing else might wake the
+                            // task when the backoff is over.
+                            match backoff.as_mut().poll(cx) {
+                                Poll::Ready(()) => cx.waker().wake_by_ref(),
+                                Poll::Pending => self.backoff = Some(backoff),
                             }
-                            self.backoff = Some(Box::pin(tokio::time::sleep(backoff_time)));
                             self.backoff_count += 1;
+                            self.backoff_from = self.next_request_id;
                             backing_off = true;
-                        } else {
-                            self.backoff_count = 0;
                         }
-                        let res = res.map_err(|_| ());
-                        for q in self.queues.values_mut() {
-                            q.on_send_result(res);
-                        }
-                        self.send_task = None;
                     }
-                    Poll::Pending => {}
+                    Ok(()) => self.backoff_count = 0,
+                }
+                let res = res.map_err(|_| ());
+                for q in self.streams.queues.iter_mut() {
+                    if q.in_flight == Some(id) {
+                        q.on_send_result(res);
+                    }
                 }
             }
-            if self.send_task.is_none()
+            // Each stream has at most one request in flight, so that its
+            // entries arrive at Loki in order.
+            if self.send_tasks.len() < self.max_concurrent_requests
                 && !backing_off
-                && self.queues.values().any(|q| q.should_send())
+                && self.streams.queues.iter().any(|q| q.should_send())
             {
-                let streams = self
+                let id = self.next_request_id;
+                self.next_request_id += 1;
+                let num_adjusted: usize = self
+                    .streams
                     .queues
-                    .values_mut()
-                    .map(|q| q.prepare_sending())
-                    .filter(|s| !s.entries.is_empty())
-                    .collect();
-                let body = self
-                    .buffer
-                    .encode(&loki::PushRequest { streams })
-                    .to_owned();
-                let request_builder = self.http_client.post(self.loki_url.clone());
-                self.send_task = Some(Box::pin(
-                    async move {
-                        request_builder
-                            .header(reqwest::header::CONTENT_TYPE, "application/x-snappy")
-                            .body(body)
-                            .send()
-                            .await?
-                            .error_for_status()?;
-                        Ok(())
-                    }
-                    .with_subscriber(NoSubscriber::default()),
-                ));
+                    .iter_mut()
+                    .filter(|q| q.is_idle())
+                    .map(|q| q.prepare_sending(id))
+                    .sum();
+                if num_adjusted != 0 {
+                    self.adjusted_timestamps
+                        .fetch_add(num_adjusted as u64, Ordering::Relaxed);
+                    drop(default_guard);
+                    tracing::debug!(
+                        num_adjusted,
+                        "adjusted timestamps to keep them increasing per stream",
+                    );
+                    default_guard = tracing::subscriber::set_default(NoSubscriber::default());
+                }
+                let BackgroundTask {
+                    buffer, streams, ..
+                } = &mut *self;
+                let body = buffer.encode(
+                    streams
+                        .queues
+                        .iter()
+                        .filter(|q| q.in_flight == Some(id))
+                        .map(|q| q.sending()),
+                );
+                let push_client = self.push_client.clone();
+                let now = self.clock.now();
+                self.send_tasks.push(SendTask {
+                    id,
+                    future: Box::pin(
+                        async move { push_client.push(body, now).await }
+                            .with_subscriber(NoSubscriber::default()),
+                    ),
+                });
             } else {
                 break;
             }
         }
-        if self.quitting && self.send_task.is_none() {
+        if self.quitting && self.send_tasks.is_empty() {
             Poll::Ready(())
         } else {
             Poll::Pending
@@ -605,46 +1114,12 @@ impl Future for BackgroundTask {
     }
 }
 
-struct Buffer {
-    encoded: Vec<u8>,
-    snappy: Vec<u8>,
-}
-
-impl Buffer {
-    pub fn new() -> Buffer {
-        Buffer {
-            encoded: Vec::new(),
-            snappy: Vec::new(),
-        }
-    }
-    pub fn encode<'a, T: prost::Message>(&'a mut self, message: &T) -> &'a [u8] {
-        self.encoded.clear();
-        message
-            .encode(&mut self.encoded)
-            .expect("protobuf encoding is infallible");
-        self.compress_encoded()
-    }
-    fn compress_encoded(&mut self) -> &[u8] {
-        self.snappy
-            .resize(snap::raw::max_compress_len(self.encoded.len()), 0);
-        // Couldn't find documentation except for the promtail source code:
-        // https://github.com/grafana/loki/blob/8c06c546ab15a568f255461f10318dae37e022d3/clients/pkg/promtail/client/batch.go#L101
-        //
-        // In the Go code, `snappy.Encode` is used, which corresponds to the
-        // snappy block format, and not the snappy stream format. hence
-        // `snap::raw` instead of `snap::write` is needed.
-        let snappy_len = snap::raw::Encoder::new()
-            .compress(&self.encoded, &mut self.snappy)
-            .expect("snappy encoding is infallible");
-        &self.snappy[..snappy_len]
-    }
-}
-
 /// Handle to cleanly shut down the `BackgroundTask`.
 ///
 /// It'll still try to send all available data and then quit.
 pub struct BackgroundTaskController {
-    sender: mpsc::Sender<Option<LokiEvent>>,
+    sender: mpsc::Sender<Option<CapturedEvent>>,
+    adjusted_timestamps: Arc<AtomicU64>,
 }
 
 impl BackgroundTaskController {
@@ -653,4 +1128,441 @@ impl BackgroundTaskController {
         // Ignore the error. If no one is listening, it already shut down.
         let _ = self.sender.send(None).await;
     }
+    /// The number of timestamps adjusted so far to keep them increasing per
+    /// stream, see [`Builder::monotonic_timestamps`].
+    pub fn adjusted_timestamps(&self) -> u64 {
+        self.adjusted_timestamps.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BackgroundTask;
+    use crate::clock::test::FakeClock;
+    use crate::push;
+    use crate::test_server;
+    use std::future;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Context;
+    use std::task::Poll;
+    use std::task::Waker;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tracing_core::Level;
+    use tracing_subscriber::layer::SubscriberExt;
+    use url::Url;
+
+    fn poll(task: &mut BackgroundTask) -> Poll<()> {
+        Pin::new(task).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    /// The lines queued or being sent, of all streams.
+    fn queued(task: &BackgroundTask) -> Vec<String> {
+        let queues = task.streams.queues.iter();
+        queues
+            .flat_map(|q| q.sending.iter().chain(&q.to_send))
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    /// Replace the requests in flight by ones that never complete, returns
+    /// their IDs.
+    fn stall(task: &mut BackgroundTask) -> Vec<u64> {
+        for send_task in &mut task.send_tasks {
+            send_task.future = Box::pin(future::pending());
+        }
+        task.send_tasks.iter().map(|t| t.id).collect()
+    }
+
+    /// Complete the request with the given ID on the next poll.
+    fn complete(task: &mut BackgroundTask, id: u64, success: bool) {
+        let send_task = task.send_tasks.iter_mut().find(|t| t.id == id).unwrap();
+        send_task.future = Box::pin(future::ready(if success {
+            Ok(())
+        } else {
+            Err("failed".into())
+        }));
+    }
+
+    /// The IDs of the requests the queues of each level are part of.
+    fn in_flight(task: &BackgroundTask) -> Vec<Option<u64>> {
+        let streams = &task.streams;
+        let by_level = streams.by_level.values();
+        by_level.map(|&i| streams.queues[i].in_flight).collect()
+    }
+
+    /// A task sending to a server that never responds, and a dispatch for
+    /// logging to it.
+    async fn stalled_task(
+        builder: crate::Builder,
+    ) -> (TcpListener, BackgroundTask, tracing::Dispatch) {
+        let (listener, url) = test_server::bind().await;
+        let (layer, task) = builder
+            .clock(FakeClock::default())
+            .build_url(Url::parse(&url).unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        (listener, task, dispatch)
+    }
+
+    #[tokio::test]
+    async fn one_request_per_stream() {
+        let builder = crate::builder().max_concurrent_requests(4);
+        let (_listener, mut task, dispatch) = stalled_task(builder).await;
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+        log(|| tracing::info!(target: "app", "first"));
+        assert!(poll(&mut task).is_pending());
+        let first = stall(&mut task);
+        assert_eq!(first.len(), 1);
+        assert_eq!(in_flight(&task), [None, None, Some(first[0]), None, None]);
+
+        // The info stream waits for its request, the warn stream gets its own.
+        log(|| tracing::info!(target: "app", "second"));
+        log(|| tracing::warn!(target: "app", "warning"));
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        assert_eq!(ids.len(), 2);
+        let info = &task.streams.queues[task.streams.by_level[Level::INFO]];
+        assert_eq!(info.in_flight, Some(first[0]));
+        assert_eq!(info.sending.len(), 1);
+        assert_eq!(info.to_send.len(), 1);
+        let second = *ids.iter().find(|&&id| id != first[0]).unwrap();
+        assert_eq!(
+            in_flight(&task),
+            [None, None, Some(first[0]), Some(second), None]
+        );
+
+        // Once the first request succeeded, the queued events are sent.
+        complete(&mut task, first[0], true);
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        assert_eq!(ids.len(), 2);
+        let third = *ids.iter().find(|&&id| id != second).unwrap();
+        assert_eq!(
+            in_flight(&task),
+            [None, None, Some(third), Some(second), None]
+        );
+        let info = &task.streams.queues[task.streams.by_level[Level::INFO]];
+        assert_eq!(info.sending.len(), 1);
+        assert!(info.sending[0].message.contains("second"));
+        assert!(info.to_send.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requeue_on_failure() {
+        let (_listener, mut task, dispatch) = stalled_task(crate::builder()).await;
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+        log(|| tracing::info!(target: "app", "first"));
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        log(|| tracing::info!(target: "app", "second"));
+        assert!(poll(&mut task).is_pending());
+
+        // The failed events are sent again, before the ones queued since.
+        complete(&mut task, ids[0], false);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 1);
+        assert!(task.send_tasks.is_empty());
+        let info = &task.streams.queues[task.streams.by_level[Level::INFO]];
+        assert_eq!(info.in_flight, None);
+        let lines = queued(&task);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("first"), "{}", lines[0]);
+        assert!(lines[1].contains("second"), "{}", lines[1]);
+
+        // The fake backoff is over immediately, retry both.
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        assert_eq!(ids.len(), 1);
+        let info = &task.streams.queues[task.streams.by_level[Level::INFO]];
+        assert_eq!(info.sending.len(), 2);
+        complete(&mut task, ids[0], true);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 0);
+        assert!(queued(&task).is_empty());
+    }
+
+    #[tokio::test]
+    async fn backoff_once_per_failure_round() {
+        let builder = crate::builder().max_concurrent_requests(2);
+        let (_listener, mut task, dispatch) = stalled_task(builder).await;
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+        log(|| tracing::info!(target: "app", "info"));
+        assert!(poll(&mut task).is_pending());
+        stall(&mut task);
+        log(|| tracing::warn!(target: "app", "warn"));
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        assert_eq!(ids.len(), 2);
+
+        // Both requests were in flight before the first failure.
+        complete(&mut task, ids[0], false);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 1);
+        complete(&mut task, ids[1], false);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 1);
+
+        // The retry was started after the backoff.
+        assert!(poll(&mut task).is_pending());
+        let ids = stall(&mut task);
+        assert_eq!(ids.len(), 1);
+        complete(&mut task, ids[0], false);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 2);
+    }
+
+    #[tokio::test]
+    async fn final_failure() {
+        let (_listener, mut task, dispatch) = stalled_task(crate::builder()).await;
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+        log(|| tracing::info!(target: "app", "first"));
+        assert!(poll(&mut task).is_pending());
+        let id = stall(&mut task)[0];
+        log(|| tracing::info!(target: "app", "second"));
+
+        // The entries might have arrived, don't send them again.
+        let to = Url::parse("http://127.0.0.1:1/elsewhere").unwrap();
+        let send_task = task.send_tasks.iter_mut().find(|t| t.id == id).unwrap();
+        send_task.future = Box::pin(future::ready(Err(push::RedirectError::FollowedByClient(
+            to,
+        )
+        .into())));
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(task.backoff_count, 0);
+        let lines = queued(&task);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("second"), "{}", lines[0]);
+    }
+
+    #[tokio::test]
+    async fn adjusted_timestamps() {
+        let (_listener, url) = test_server::bind().await;
+        let (layer, controller, mut task) = crate::builder()
+            .clock(FakeClock::default())
+            .monotonic_timestamps(true)
+            .build_controller_url(Url::parse(&url).unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+
+        // The clock stands still, all but the first entry are moved forward.
+        log(|| {
+            tracing::info!(target: "app", "first");
+            tracing::info!(target: "app", "second");
+            tracing::info!(target: "app", "third");
+        });
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(controller.adjusted_timestamps(), 2);
+        let ids = stall(&mut task);
+        complete(&mut task, ids[0], true);
+        log(|| tracing::info!(target: "app", "fourth"));
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(controller.adjusted_timestamps(), 3);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit() {
+        let builder = crate::builder().max_concurrent_requests(2);
+        let (_listener, mut task, dispatch) = stalled_task(builder).await;
+        let log = |f: fn()| tracing::dispatcher::with_default(&dispatch, f);
+        log(|| tracing::info!(target: "app", "info"));
+        assert!(poll(&mut task).is_pending());
+        let first = stall(&mut task)[0];
+        log(|| tracing::warn!(target: "app", "warn"));
+        assert!(poll(&mut task).is_pending());
+        stall(&mut task);
+        log(|| tracing::error!(target: "app", "error"));
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(stall(&mut task).len(), 2);
+        let error = &task.streams.queues[task.streams.by_level[Level::ERROR]];
+        assert_eq!(error.in_flight, None);
+        assert_eq!(error.to_send.len(), 1);
+
+        complete(&mut task, first, true);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(stall(&mut task).len(), 2);
+        let error = &task.streams.queues[task.streams.by_level[Level::ERROR]];
+        assert!(error.in_flight.is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrency_capped_at_streams() {
+        let builder = crate::builder().max_concurrent_requests(100);
+        let (_listener, task, _) = stalled_task(builder).await;
+        assert_eq!(task.max_concurrent_requests, 5);
+        let builder = crate::builder()
+            .label("host", "mine")
+            .unwrap()
+            .single_stream(true)
+            .max_concurrent_requests(100);
+        let (_listener, task, _) = stalled_task(builder).await;
+        assert_eq!(task.max_concurrent_requests, 1);
+        let builder = crate::builder()
+            .level_field("severity")
+            .max_concurrent_requests(100);
+        let (_listener, task, _) = stalled_task(builder).await;
+        assert_eq!(task.max_concurrent_requests, 5 + 16);
+    }
+
+    #[tokio::test]
+    async fn level_overrides() {
+        let builder = crate::builder().level_field("severity");
+        let (_listener, mut task, dispatch) = stalled_task(builder).await;
+        tracing::dispatcher::with_default(&dispatch, || {
+            tracing::error!(target: "app", severity = "critical", "critical");
+            tracing::error!(target: "app", severity = "info", "info");
+            tracing::error!(target: "app", severity = "", "empty");
+            tracing::error!(target: "app", severity = "\u{1b}[31m", "control");
+            let long = "x".repeat(2049);
+            tracing::error!(target: "app", severity = &long[..], "long");
+            for i in 0..20 {
+                let value = format!("value{}", i);
+                tracing::error!(target: "app", severity = &value[..], "many");
+            }
+        });
+        assert!(poll(&mut task).is_pending());
+        let streams = &task.streams;
+        let messages = |value: &str| -> Vec<String> {
+            let i = streams.by_level_value[value];
+            let q = &streams.queues[i];
+            let events = q.sending.iter().chain(&q.to_send);
+            events
+                .map(|e| {
+                    let line: serde_json::Value = serde_json::from_str(&e.message).unwrap();
+                    line["message"].as_str().unwrap().to_owned()
+                })
+                .collect()
+        };
+        assert_eq!(messages("critical"), ["critical"]);
+        assert_eq!(messages("info"), ["info"]);
+        assert_eq!(messages("value14"), ["many"]);
+        assert!(!streams.by_level_value.contains_key("value15"));
+        assert_eq!(streams.queues.len(), 5 + 16);
+        let error = messages("error");
+        assert_eq!(error[..3], ["empty", "control", "long"]);
+        assert_eq!(error[3..], ["many"; 5]);
+    }
+
+    #[tokio::test]
+    async fn http_client_options() {
+        let url = Url::parse("http://127.0.0.1:1").unwrap();
+        let builder = || crate::builder().http_client(reqwest::Client::new());
+        assert!(builder().build_url(url.clone()).is_ok());
+        let error = builder()
+            .connect_timeout(Duration::from_secs(5))
+            .build_url(url.clone())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "TLS and connection options don't apply to a supplied HTTP client",
+        );
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        assert!(builder()
+            .danger_accept_invalid_certs(true)
+            .build_url(url)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn dedup_timer_fires_early() {
+        let clock = FakeClock::default();
+        let (layer, mut task) = crate::builder()
+            .clock(clock.clone())
+            .dedup_window(Duration::from_secs(10))
+            .build_url(Url::parse("http://127.0.0.1:1").unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            for _ in 0..3 {
+                tracing::info!(target: "app", "repeated");
+            }
+        });
+        let timers = || {
+            let sleeps = clock.sleeps.lock().unwrap();
+            sleeps
+                .iter()
+                .filter(|&&d| d == Duration::from_secs(10))
+                .count()
+        };
+        // The fake timers fire immediately, before the deadline.
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(timers(), 1);
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(timers(), 2);
+        assert_eq!(queued(&task).len(), 1);
+        clock.advance(Duration::from_secs(10));
+        assert!(poll(&mut task).is_pending());
+        let queued = queued(&task);
+        assert_eq!(queued.len(), 2);
+        assert!(queued[1].contains(r#""repeat_count":2"#), "{}", queued[1]);
+    }
+
+    #[tokio::test]
+    async fn span_fields() {
+        let (layer, mut task) = crate::builder()
+            .build_url(Url::parse("http://127.0.0.1:1").unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let outer = tracing::info_span!("outer", user = "alice");
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("inner", attempt = 1);
+            let _inner = inner.enter();
+            tracing::info!("first");
+            tracing::info!("cached");
+            outer.record("user", &"bob");
+            tracing::info!("outer changed");
+            inner.record("attempt", &2);
+            tracing::info!("inner changed");
+        });
+        assert!(poll(&mut task).is_pending());
+        let fields: Vec<_> = queued(&task)
+            .iter()
+            .map(|line| {
+                let line: serde_json::Value = serde_json::from_str(line).unwrap();
+                (line["user"].clone(), line["attempt"].clone())
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("alice".into(), 1.into()),
+                ("alice".into(), 1.into()),
+                ("bob".into(), 1.into()),
+                ("bob".into(), 2.into()),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn suppressed_summary() {
+        let (layer, mut task) = crate::builder()
+            .rate_limit(1.0, 1)
+            .unwrap()
+            .suppressed_summary_interval(Duration::from_millis(50))
+            // Nothing should be listening on port 1.
+            .build_url(Url::parse("http://127.0.0.1:1").unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            for _ in 0..3 {
+                tracing::warn!(target: "app", "flood");
+            }
+        });
+        assert!(poll(&mut task).is_pending());
+        assert_eq!(queued(&task).len(), 1);
+        // The flood stopped, but the suppressed events are still reported.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(poll(&mut task).is_pending());
+        let queued = queued(&task);
+        assert_eq!(queued.len(), 2);
+        assert!(
+            queued[1].contains(r#""message":"suppressed 2 events from app ("#),
+            "{}",
+            queued[1],
+        );
+    }
 }
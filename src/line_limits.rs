@@ -0,0 +1,345 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// What to do with log lines exceeding the maximum line size.
+///
+/// See [`Builder::max_line_size`](crate::Builder::max_line_size).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineOverflow {
+    /// Shorten the string values of the line, like the message and the field
+    /// values, until it fits. The line is marked with `"_truncated":true`
+    /// and `"_original_size"`, its original length in bytes.
+    #[default]
+    Truncate,
+    /// Split the line into several log entries, each a JSON object of the
+    /// form `{"_split_id":"…","_part":1,"_parts":3,"_chunk":"…"}`.
+    ///
+    /// All parts of a line share the same `_split_id`, and concatenating
+    /// their `_chunk`s in the order of `_part` gives the original line. The
+    /// timestamps of the parts are one nanosecond apart, to keep them in
+    /// order.
+    ///
+    /// If the maximum line size doesn't leave room for the fields of the
+    /// parts, about 80 bytes, lines are truncated instead.
+    Split,
+}
+
+#[derive(Clone, Default)]
+pub struct LineLimits {
+    pub max_line_size: Option<usize>,
+    pub max_field_size: Option<usize>,
+    pub overflow: LineOverflow,
+}
+
+/// Returns the largest index not greater than `index` that lies on a `char`
+/// boundary of `s`.
+pub fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    (0..=index).rev().find(|&i| s.is_char_boundary(i)).unwrap()
+}
+
+/// Cap a field value to at most `max` bytes, marking it with an ellipsis if
+/// it was shortened.
+pub fn truncate_field(value: &mut String, max: usize) {
+    if let Cow::Owned(capped) = capped(value, max) {
+        *value = capped;
+    }
+}
+
+/// `value` cut to at most `max` bytes, see [`truncate_field`].
+pub fn capped(value: &str, max: usize) -> Cow<'_, str> {
+    const ELLIPSIS: &str = "…";
+    if value.len() <= max {
+        return Cow::Borrowed(value);
+    }
+    if max < ELLIPSIS.len() {
+        return Cow::Owned(value[..floor_char_boundary(value, max)].into());
+    }
+    let mut result = value[..floor_char_boundary(value, max - ELLIPSIS.len())].to_owned();
+    result.push_str(ELLIPSIS);
+    Cow::Owned(result)
+}
+
+/// A JSON value whose strings are serialized with at most the given number
+/// of bytes, if any.
+pub struct CappedValue<'a>(pub &'a serde_json::Value, pub Option<usize>);
+
+impl<'a> Serialize for CappedValue<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde_json::Value;
+        let max = match self.1 {
+            Some(max) => max,
+            None => return self.0.serialize(serializer),
+        };
+        match self.0 {
+            Value::String(s) => serializer.serialize_str(&capped(s, max)),
+            Value::Array(values) => {
+                serializer.collect_seq(values.iter().map(|v| CappedValue(v, Some(max))))
+            }
+            Value::Object(map) => {
+                serializer.collect_map(map.iter().map(|(k, v)| (k, CappedValue(v, Some(max)))))
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+/// The maximum number of bytes a `char` takes up in a JSON string.
+const MAX_ESCAPED_LEN: usize = 6;
+
+/// The number of bytes `c` takes up in a JSON string.
+fn escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        '\0'..='\u{1f}' => 6,
+        _ => c.len_utf8(),
+    }
+}
+
+/// A new ID for the parts of a split line, unique with high probability.
+fn split_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+fn split_part(id: &str, part: usize, parts: usize, chunk: &str) -> String {
+    format!(
+        r#"{{"_split_id":"{}","_part":{},"_parts":{},"_chunk":{}}}"#,
+        id,
+        part,
+        parts,
+        serde_json::to_string(chunk).expect("json serialization shouldn't fail"),
+    )
+}
+
+impl LineLimits {
+    /// Apply the line size limit to `line`, calling `emit` for each resulting
+    /// line with the offset of its timestamp.
+    ///
+    /// `truncated(max_value_len, original_size)` must serialize the line
+    /// again, with string values cut to `max_value_len` bytes and the
+    /// truncation markers.
+    pub fn apply<T, F>(&self, line: String, truncated: T, mut emit: F)
+    where
+        T: Fn(usize, usize) -> String,
+        F: FnMut(Duration, String),
+    {
+        let max = match self.max_line_size {
+            Some(max) if line.len() > max => max,
+            _ => return emit(Duration::ZERO, line),
+        };
+        let size = line.len();
+        let truncate = |emit: &mut F| {
+            // The length of the line only grows with the maximum length of
+            // its values, look for the largest one that fits.
+            let (mut lo, mut hi) = (0, size);
+            let mut best = None;
+            while lo < hi {
+                let mid = hi - (hi - lo) / 2;
+                let candidate = truncated(mid, size);
+                if candidate.len() <= max {
+                    best = Some(candidate);
+                    lo = mid;
+                } else {
+                    hi = mid - 1;
+                }
+            }
+            let line = best
+                .or_else(|| Some(truncated(0, size)).filter(|l| l.len() <= max))
+                // Even the keys and non-string values don't fit.
+                .unwrap_or_else(|| format!(r#"{{"_truncated":true,"_original_size":{}}}"#, size));
+            emit(Duration::ZERO, line);
+        };
+        match self.overflow {
+            LineOverflow::Truncate => truncate(&mut emit),
+            LineOverflow::Split => {
+                let id = split_id();
+                // There are at most as many parts as bytes in the line.
+                let overhead = split_part(&id, size, size, "").len();
+                let room = max.saturating_sub(overhead);
+                // Every part must fit at least one escaped character.
+                if room < MAX_ESCAPED_LEN {
+                    return truncate(&mut emit);
+                }
+                let mut chunks = Vec::new();
+                let mut rest = &line[..];
+                while !rest.is_empty() {
+                    let mut len = 0;
+                    let mut cut = 0;
+                    for c in rest.chars() {
+                        len += escaped_len(c);
+                        if len > room {
+                            break;
+                        }
+                        cut += c.len_utf8();
+                    }
+                    chunks.push(&rest[..cut]);
+                    rest = &rest[cut..];
+                }
+                let parts = chunks.len();
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    emit(
+                        Duration::from_nanos(i as u64),
+                        split_part(&id, i + 1, parts, chunk),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::capped;
+    use super::truncate_field;
+    use super::CappedValue;
+    use super::LineLimits;
+    use super::LineOverflow;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// A line with a message and a number, `truncated` like the event
+    /// formatter does.
+    fn line(message: &str, max_value_len: Option<usize>, original_size: Option<usize>) -> String {
+        let mut line = format!(
+            r#"{{"message":{},"count":3"#,
+            serde_json::to_string(&CappedValue(&json!(message), max_value_len)).unwrap(),
+        );
+        if let Some(size) = original_size {
+            line.push_str(&format!(r#","_truncated":true,"_original_size":{}"#, size));
+        }
+        line.push('}');
+        line
+    }
+
+    fn apply(limits: &LineLimits, message: &str) -> Vec<(Duration, String)> {
+        let mut result = Vec::new();
+        limits.apply(
+            line(message, None, None),
+            |max, size| line(message, Some(max), Some(size)),
+            |offset, l| result.push((offset, l)),
+        );
+        result
+    }
+
+    fn lines(result: Vec<(Duration, String)>) -> Vec<String> {
+        result.into_iter().map(|(_, l)| l).collect()
+    }
+
+    #[test]
+    fn unlimited() {
+        let limits = LineLimits::default();
+        assert_eq!(
+            lines(apply(&limits, "abc")),
+            [r#"{"message":"abc","count":3}"#]
+        );
+    }
+
+    #[test]
+    fn truncate() {
+        let limits = LineLimits {
+            max_line_size: Some(70),
+            ..Default::default()
+        };
+        assert_eq!(
+            lines(apply(&limits, "short")),
+            [r#"{"message":"short","count":3}"#],
+        );
+        let result = lines(apply(&limits, &"x".repeat(100)));
+        assert_eq!(
+            result,
+            [r#"{"message":"xxxx…","count":3,"_truncated":true,"_original_size":124}"#],
+        );
+        assert_eq!(result[0].len(), 70);
+        assert!(serde_json::from_str::<serde_json::Value>(&result[0]).is_ok());
+
+        // Not even the markers fit.
+        let limits = LineLimits {
+            max_line_size: Some(20),
+            ..Default::default()
+        };
+        assert_eq!(
+            lines(apply(&limits, &"x".repeat(100))),
+            [r#"{"_truncated":true,"_original_size":124}"#],
+        );
+    }
+
+    #[test]
+    fn split() {
+        let limits = LineLimits {
+            max_line_size: Some(80),
+            overflow: LineOverflow::Split,
+            ..Default::default()
+        };
+        let message = "\"quoted\" äöü\n".repeat(5);
+        let original = line(&message, None, None);
+        let result = apply(&limits, &message);
+        assert!(result.len() > 1);
+        let mut joined = String::new();
+        let mut ids = Vec::new();
+        for (i, (offset, piece)) in result.iter().enumerate() {
+            assert!(piece.len() <= 80, "{}", piece);
+            assert_eq!(*offset, Duration::from_nanos(i as u64));
+            let piece: serde_json::Value = serde_json::from_str(piece).unwrap();
+            assert_eq!(piece["_part"], i + 1);
+            assert_eq!(piece["_parts"], result.len());
+            ids.push(piece["_split_id"].as_str().unwrap().to_owned());
+            joined.push_str(piece["_chunk"].as_str().unwrap());
+        }
+        assert_eq!(joined, original);
+        assert!(ids.iter().all(|id| *id == ids[0]));
+
+        let other = apply(&limits, &message);
+        assert!(!other[0].1.contains(&ids[0]));
+
+        // Parts wouldn't fit, truncate instead.
+        let limits = LineLimits {
+            max_line_size: Some(70),
+            overflow: LineOverflow::Split,
+            ..Default::default()
+        };
+        assert_eq!(
+            lines(apply(&limits, &"x".repeat(100))),
+            [r#"{"message":"xxxx…","count":3,"_truncated":true,"_original_size":124}"#],
+        );
+    }
+
+    #[test]
+    fn capped_value() {
+        let value = json!({"a": ["abcdefgh", 12345678], "b": "äöü"});
+        assert_eq!(
+            serde_json::to_string(&CappedValue(&value, Some(5))).unwrap(),
+            r#"{"a":["ab…",12345678],"b":"ä…"}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&CappedValue(&value, None)).unwrap(),
+            serde_json::to_string(&value).unwrap(),
+        );
+        assert_eq!(capped("abc", 2), "ab");
+    }
+
+    #[test]
+    fn field() {
+        let mut value = String::from("abcdefgh");
+        truncate_field(&mut value, 8);
+        assert_eq!(value, "abcdefgh");
+        truncate_field(&mut value, 7);
+        assert_eq!(value, "abcd…");
+        let mut value = String::from("äöü");
+        truncate_field(&mut value, 5);
+        assert_eq!(value, "ä…");
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+use std::time::SystemTime;
+
+use super::LokiEvent;
+
+/// Make the timestamps of a batch of a stream strictly increasing.
+///
+/// Sorts the batch by timestamp, keeping the order of events with equal
+/// timestamps, and moves timestamps that aren't after the previous one (or
+/// `after` for the first event) forward to one nanosecond past it. Returns
+/// the number of adjusted timestamps.
+pub fn make_monotonic(events: &mut [LokiEvent], mut after: Option<SystemTime>) -> usize {
+    events.sort_by_key(|e| e.timestamp);
+    let mut adjusted = 0;
+    for event in events {
+        if let Some(after) = after {
+            if event.timestamp <= after {
+                event.timestamp = after + Duration::from_nanos(1);
+                adjusted += 1;
+            }
+        }
+        after = Some(event.timestamp);
+    }
+    adjusted
+}
+
+#[cfg(test)]
+mod test {
+    use super::make_monotonic;
+    use crate::LokiEvent;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+    use tracing_core::Level;
+
+    fn event(nanos: u64, message: &str) -> LokiEvent {
+        LokiEvent {
+            trigger_send: true,
+            timestamp: UNIX_EPOCH + Duration::from_nanos(nanos),
+            level: Level::INFO,
+            level_value: None,
+            message: message.into(),
+        }
+    }
+
+    fn entries(events: &[LokiEvent]) -> Vec<(u128, &str)> {
+        events
+            .iter()
+            .map(|e| {
+                let nanos = e.timestamp.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+                (nanos, &e.message[..])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sort_and_nudge() {
+        let mut events = vec![
+            event(10, "a"),
+            event(5, "b"),
+            event(10, "c"),
+            event(10, "d"),
+            event(11, "e"),
+            event(20, "f"),
+        ];
+        assert_eq!(make_monotonic(&mut events, None), 3);
+        assert_eq!(
+            entries(&events),
+            [
+                (5, "b"),
+                (10, "a"),
+                (11, "c"),
+                (12, "d"),
+                (13, "e"),
+                (20, "f")
+            ],
+        );
+    }
+
+    #[test]
+    fn after_previous_batch() {
+        let mut events = vec![event(3, "a"), event(8, "b"), event(9, "c")];
+        let after = Some(UNIX_EPOCH + Duration::from_nanos(7));
+        assert_eq!(make_monotonic(&mut events, after), 3);
+        assert_eq!(entries(&events), [(8, "a"), (9, "b"), (10, "c")]);
+        assert_eq!(make_monotonic(&mut [], after), 0);
+    }
+}
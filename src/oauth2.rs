@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+use std::time::SystemTime;
+use url::Url;
+
+/// Refresh tokens at most this long before they expire.
+const MAX_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// When to fetch a new token that expires in `expires_in`, shortly before it
+/// expires.
+pub(crate) fn refresh_at(now: SystemTime, expires_in: Duration) -> Option<SystemTime> {
+    now.checked_add(expires_in - cmp::min(expires_in / 10, MAX_REFRESH_MARGIN))
+}
+
+/// Credentials for obtaining access tokens with the OAuth2 client
+/// credentials grant, see [`Builder::oauth2`](crate::Builder::oauth2).
+///
+/// The client authenticates to the token endpoint with HTTP basic
+/// authentication, as recommended by
+/// [RFC 6749](https://www.rfc-editor.org/rfc/rfc6749#section-2.3.1).
+///
+/// # Example
+///
+/// ```
+/// use tracing_loki::OAuth2;
+/// use url::Url;
+///
+/// let credentials = OAuth2::client_credentials(
+///     Url::parse("https://auth.example.com/oauth2/token").unwrap(),
+///     "loki-shipper",
+///     "secret",
+/// )
+/// .scope("logs:write")
+/// .param("audience", "https://loki.example.com");
+/// ```
+#[derive(Clone)]
+pub struct OAuth2 {
+    token_url: Url,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    params: Vec<(String, String)>,
+}
+
+impl OAuth2 {
+    /// Obtain tokens from `token_url` using the given client ID and secret.
+    pub fn client_credentials<S: Into<String>, T: Into<String>>(
+        token_url: Url,
+        client_id: S,
+        client_secret: T,
+    ) -> OAuth2 {
+        OAuth2 {
+            token_url,
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+    /// Request the scope `scope`. Can be called multiple times.
+    pub fn scope<S: Into<String>>(mut self, scope: S) -> OAuth2 {
+        self.scopes.push(scope.into());
+        self
+    }
+    /// Send an additional form parameter to the token endpoint, like the
+    /// `audience` or `resource` some providers require.
+    pub fn param<S: Into<String>, T: Into<String>>(mut self, name: S, value: T) -> OAuth2 {
+        self.params.push((name.into(), value.into()));
+        self
+    }
+    /// Request a new access token.
+    pub(crate) async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        now: SystemTime,
+    ) -> Result<AccessToken, TokenEndpointError> {
+        let mut form = vec![("grant_type", "client_credentials".to_owned())];
+        if !self.scopes.is_empty() {
+            form.push(("scope", self.scopes.join(" ")));
+        }
+        form.extend(self.params.iter().map(|(n, v)| (&n[..], v.clone())));
+        let request = client
+            .post(self.token_url.clone())
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&form);
+        let response = request.send().await.map_err(TokenEndpointError::Http)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(TokenEndpointError::Http)?;
+        parse_response(status, &body, now)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// A cached access token.
+pub(crate) struct AccessToken {
+    pub token: String,
+    /// When to fetch a new token, shortly before it expires.
+    pub refresh_at: Option<SystemTime>,
+}
+
+impl AccessToken {
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        self.refresh_at.is_none_or(|refresh_at| now < refresh_at)
+    }
+}
+
+fn parse_response(
+    status: reqwest::StatusCode,
+    body: &[u8],
+    now: SystemTime,
+) -> Result<AccessToken, TokenEndpointError> {
+    if !status.is_success() {
+        let error = serde_json::from_slice::<ErrorResponse>(body).ok();
+        return Err(TokenEndpointError::Status(status, error));
+    }
+    let response: TokenResponse =
+        serde_json::from_slice(body).map_err(TokenEndpointError::InvalidResponse)?;
+    if reqwest::header::HeaderValue::from_str(&format!("Bearer {}", response.access_token)).is_err()
+    {
+        return Err(TokenEndpointError::InvalidToken);
+    }
+    let refresh_at = response
+        .expires_in
+        .and_then(|expires_in| refresh_at(now, Duration::from_secs(expires_in)));
+    Ok(AccessToken {
+        token: response.access_token,
+        refresh_at,
+    })
+}
+
+#[derive(Debug)]
+pub(crate) enum TokenEndpointError {
+    Http(reqwest::Error),
+    Status(reqwest::StatusCode, Option<ErrorResponse>),
+    InvalidResponse(serde_json::Error),
+    InvalidToken,
+}
+
+impl fmt::Display for TokenEndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TokenEndpointError::*;
+        write!(f, "couldn't get OAuth2 access token: ")?;
+        match self {
+            Http(e) => write!(f, "{}", e),
+            Status(status, None) => write!(f, "HTTP status {}", status),
+            Status(status, Some(error)) => {
+                write!(f, "HTTP status {}, {}", status, error.error)?;
+                if let Some(description) = &error.error_description {
+                    write!(f, ": {}", description)?;
+                }
+                Ok(())
+            }
+            InvalidResponse(e) => write!(f, "invalid response: {}", e),
+            InvalidToken => write!(f, "invalid characters in access token"),
+        }
+    }
+}
+
+impl error::Error for TokenEndpointError {}
+
+#[cfg(test)]
+mod test {
+    use super::parse_response;
+    use super::OAuth2;
+    use crate::test_server;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+    use tracing_subscriber::layer::SubscriberExt;
+    use url::Url;
+
+    #[test]
+    fn response() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let token = parse_response(
+            StatusCode::OK,
+            br#"{"access_token":"abc","token_type":"Bearer","expires_in":3600}"#,
+            now,
+        )
+        .unwrap();
+        assert_eq!(token.token, "abc");
+        assert_eq!(token.refresh_at, Some(now + Duration::from_secs(3540)));
+        assert!(token.is_fresh(now + Duration::from_secs(3539)));
+        assert!(!token.is_fresh(now + Duration::from_secs(3540)));
+
+        let short = parse_response(
+            StatusCode::OK,
+            br#"{"access_token":"abc","expires_in":60}"#,
+            now,
+        );
+        assert_eq!(
+            short.unwrap().refresh_at,
+            Some(now + Duration::from_secs(54))
+        );
+        let forever = parse_response(StatusCode::OK, br#"{"access_token":"abc"}"#, now).unwrap();
+        assert!(forever.is_fresh(now + Duration::from_secs(1_000_000)));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |status, body: &str| {
+            parse_response(status, body.as_bytes(), UNIX_EPOCH)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            error(
+                StatusCode::UNAUTHORIZED,
+                r#"{"error":"invalid_client","error_description":"bad secret"}"#,
+            ),
+            "couldn't get OAuth2 access token: HTTP status 401 Unauthorized, invalid_client: bad secret",
+        );
+        assert_eq!(
+            error(StatusCode::BAD_GATEWAY, "<html>"),
+            "couldn't get OAuth2 access token: HTTP status 502 Bad Gateway",
+        );
+        assert!(error(StatusCode::OK, r#"{"token":"abc"}"#).contains("invalid response"));
+        assert_eq!(
+            error(StatusCode::OK, r#"{"access_token":"a\nb"}"#),
+            "couldn't get OAuth2 access token: invalid characters in access token",
+        );
+    }
+
+    #[tokio::test]
+    async fn client_credentials() {
+        let (listener, url) = test_server::bind().await;
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let mut tokens = ["first", "second"].into_iter();
+            for _ in 0..4 {
+                let (stream, request) = test_server::accept(&listener).await;
+                let line = request.head.lines().next().unwrap().to_owned();
+                let auth = request.header("authorization").unwrap().to_owned();
+                if line.starts_with("POST /token ") {
+                    let body = String::from_utf8(request.body).unwrap();
+                    assert_eq!(body, "grant_type=client_credentials&scope=logs%3Awrite");
+                    let token = tokens.next().unwrap();
+                    let response = format!(r#"{{"access_token":"{}","expires_in":3600}}"#, token);
+                    test_server::respond(stream, "200 OK", &response).await;
+                } else if auth == "Bearer second" {
+                    test_server::respond(stream, "204 No Content", "").await;
+                } else {
+                    test_server::respond(stream, "401 Unauthorized", "").await;
+                }
+                requests.push(format!("{} {}", line.split(' ').nth(1).unwrap(), auth));
+            }
+            requests
+        });
+        let credentials = OAuth2::client_credentials(
+            Url::parse(&format!("{}/token", url)).unwrap(),
+            "client",
+            "secret",
+        )
+        .scope("logs:write");
+        let (layer, controller, task) = crate::builder()
+            .oauth2(credentials)
+            .build_controller_url(Url::parse(&url).unwrap())
+            .unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || tracing::info!(target: "app", "hello"));
+        let task = tokio::spawn(task);
+        controller.shutdown().await;
+        task.await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                // Base64 of `client:secret`.
+                "/token Basic Y2xpZW50OnNlY3JldA==",
+                "/loki/api/v1/push Bearer first",
+                "/token Basic Y2xpZW50OnNlY3JldA==",
+                "/loki/api/v1/push Bearer second",
+            ],
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use bytes::Bytes;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use reqwest::StatusCode;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use url::Url;
+
+use super::auth::Auth;
+
+/// The maximum number of redirects followed for a push request.
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug)]
+struct BadRedirect {
+    status: u16,
+    to: Url,
+}
+
+impl fmt::Display for BadRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Following such a redirect drops the request body, and will likely
+        // give an HTTP 200 response even though nobody ever looked at the POST
+        // body.
+        //
+        // This can e.g. happen for login redirects when you post to a
+        // login-protected URL.
+        write!(f, "invalid HTTP {} redirect to {}", self.status, self.to)
+    }
+}
+
+impl error::Error for BadRedirect {}
+
+#[derive(Debug)]
+pub enum RedirectError {
+    MissingLocation(u16),
+    InvalidLocation(u16),
+    TooMany,
+    /// A supplied client followed a redirect itself, possibly dropping the
+    /// body.
+    FollowedByClient(Url),
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RedirectError::*;
+        match self {
+            MissingLocation(status) => write!(f, "HTTP {} redirect without location", status),
+            InvalidLocation(status) => write!(f, "HTTP {} redirect to invalid location", status),
+            TooMany => write!(f, "more than {} redirects", MAX_REDIRECTS),
+            FollowedByClient(to) => write!(
+                f,
+                "the HTTP client followed a redirect to {}, \
+                 it should be built with `redirect::Policy::none()`",
+                to,
+            ),
+        }
+    }
+}
+
+impl error::Error for RedirectError {}
+
+/// A push request that didn't complete in time, see
+/// [`Builder::request_timeout`](crate::Builder::request_timeout).
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "push request timed out after {:?}", self.0)
+    }
+}
+
+impl error::Error for TimedOut {}
+
+/// Whether a push request that failed with `error` must not be retried.
+///
+/// This is the case if a supplied client followed a redirect itself: it
+/// might have dropped the body, e.g. by turning a `303` into a `GET`, or
+/// delivered the entries, e.g. for a `307`, so retrying could duplicate them.
+pub fn is_final(error: &(dyn error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<RedirectError>(),
+        Some(RedirectError::FollowedByClient(_)),
+    )
+}
+
+/// Settings of the HTTP client created unless one is supplied.
+#[derive(Clone, Default, PartialEq)]
+pub struct ConnectionOptions {
+    pub connect_timeout: Option<Duration>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub http2_prior_knowledge: bool,
+}
+
+/// Sends push requests to Loki.
+///
+/// Everything except for the connection handling is done per request, so
+/// that it also applies to a client supplied with
+/// [`Builder::http_client`](crate::Builder::http_client).
+pub struct PushClient {
+    pub client: reqwest::Client,
+    pub url: Url,
+    /// The user agent, content type and the headers set with
+    /// [`Builder::http_header`](crate::Builder::http_header).
+    pub headers: HeaderMap,
+    pub auth: Option<Arc<Auth>>,
+    /// The timeout of each push, including redirects, retries and
+    /// obtaining credentials.
+    pub timeout: Option<Duration>,
+}
+
+impl PushClient {
+    /// Create the client used unless one is supplied. It doesn't follow
+    /// redirects, they are handled by [`PushClient::push`].
+    pub fn default_client(options: &ConnectionOptions) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .tcp_keepalive(options.tcp_keepalive);
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = options.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if options.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder
+    }
+    pub fn default_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            HeaderValue::from_static(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            )),
+        );
+        headers.insert(
+            reqwest::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-snappy"),
+        );
+        headers
+    }
+    /// Send `body` to Loki.
+    ///
+    /// Errors, including timeouts, are returned to the background task,
+    /// which retries after a backoff.
+    ///
+    /// `301`, `307` and `308` redirects are followed, repeating the request
+    /// to the new location. `302` and `303` redirects are an error, since
+    /// they ask for the body to be dropped. A `401` response is retried
+    /// once with refreshed credentials, if possible.
+    ///
+    /// Redirects followed by a supplied client itself are an error, see
+    /// [`is_final`].
+    pub async fn push(
+        &self,
+        body: Bytes,
+        now: SystemTime,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.send(body, now))
+                .await
+                .map_err(|_| TimedOut(timeout))?,
+            None => self.send(body, now).await,
+        }
+    }
+    async fn send(
+        &self,
+        body: Bytes,
+        now: SystemTime,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut url = self.url.clone();
+        let mut redirects = 0;
+        let mut refreshed = false;
+        loop {
+            let mut request = self
+                .client
+                .post(url.clone())
+                .headers(self.headers.clone())
+                .body(body.clone());
+            if let Some(auth) = &self.auth {
+                request = auth.apply(request, &self.client, now, refreshed).await?;
+            }
+            let response = request.send().await?;
+            let status = response.status();
+            if status == StatusCode::UNAUTHORIZED
+                && !refreshed
+                && self.auth.as_ref().is_some_and(|a| a.can_refresh())
+            {
+                refreshed = true;
+                continue;
+            }
+            if response.url() != &url {
+                return Err(RedirectError::FollowedByClient(response.url().clone()).into());
+            }
+            if !status.is_redirection() {
+                response.error_for_status()?;
+                return Ok(());
+            }
+            let code = status.as_u16();
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .ok_or(RedirectError::MissingLocation(code))?;
+            let to = location
+                .to_str()
+                .ok()
+                .and_then(|l| url.join(l).ok())
+                .ok_or(RedirectError::InvalidLocation(code))?;
+            match status {
+                StatusCode::MOVED_PERMANENTLY
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => {}
+                _ => return Err(BadRedirect { status: code, to }.into()),
+            }
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(RedirectError::TooMany.into());
+            }
+            url = to;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_final;
+    use super::PushClient;
+    use super::RedirectError;
+    use super::TimedOut;
+    use crate::auth::Auth;
+    use crate::test_server;
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+    use url::Url;
+
+    /// Push to a server answering with `responses`, given as status line
+    /// and location. Returns the method and path, and the body of each
+    /// request.
+    async fn push(
+        client: reqwest::Client,
+        responses: &'static [(&'static str, &'static str)],
+    ) -> (Result<(), String>, Vec<(String, String)>) {
+        let (listener, url) = test_server::bind().await;
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for &(status, location) in responses {
+                let (stream, request) = test_server::accept(&listener).await;
+                let mut line = request.head.split(' ');
+                let path = format!("{} {}", line.next().unwrap(), line.next().unwrap());
+                let user_agent = request.header("user-agent").unwrap().to_owned();
+                assert!(user_agent.starts_with("tracing-loki/"), "{}", user_agent);
+                assert_eq!(request.header("x-custom"), Some("value"));
+                let body = String::from_utf8(request.body).unwrap();
+                requests.push((path, body));
+                let headers = match location {
+                    "" => String::new(),
+                    _ => format!("location: {}\r\n", location),
+                };
+                test_server::respond_with_headers(stream, status, &headers, "").await;
+            }
+            requests
+        });
+        let mut headers = PushClient::default_headers();
+        headers.insert("x-custom", "value".parse().unwrap());
+        let push_client = PushClient {
+            client,
+            url: Url::parse(&format!("{}/loki/api/v1/push", url)).unwrap(),
+            headers,
+            auth: None,
+            timeout: None,
+        };
+        let result = push_client
+            .push(Bytes::from_static(b"body"), SystemTime::now())
+            .await
+            .map_err(|e| e.to_string());
+        (result, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn redirects() {
+        let client = PushClient::default_client(&Default::default())
+            .build()
+            .unwrap();
+        let (result, requests) = push(
+            client.clone(),
+            &[
+                ("301 Moved Permanently", "/a"),
+                ("307 Temporary Redirect", "/b"),
+                ("308 Permanent Redirect", "/c"),
+                ("204 No Content", ""),
+            ],
+        )
+        .await;
+        result.unwrap();
+        let paths: Vec<_> = requests.iter().map(|(p, _)| &p[..]).collect();
+        assert_eq!(
+            paths,
+            ["POST /loki/api/v1/push", "POST /a", "POST /b", "POST /c"],
+        );
+        assert!(requests.iter().all(|(_, body)| body == "body"));
+
+        let (result, _) = push(client.clone(), &[("302 Found", "/login")]).await;
+        assert!(result
+            .unwrap_err()
+            .starts_with("invalid HTTP 302 redirect to http://"));
+        let (result, _) = push(client, &[("500 Internal Server Error", "")]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_client() {
+        // This client follows the redirect itself, dropping the body.
+        let client = reqwest::Client::builder().build().unwrap();
+        let (result, requests) = push(
+            client.clone(),
+            &[("303 See Other", "/elsewhere"), ("200 OK", "")],
+        )
+        .await;
+        assert_eq!(requests[1].0, "GET /elsewhere");
+        assert!(result
+            .unwrap_err()
+            .contains("the HTTP client followed a redirect to http://"));
+
+        // Even if it kept the body, the request mustn't be retried.
+        let (result, requests) = push(
+            client,
+            &[("307 Temporary Redirect", "/elsewhere"), ("200 OK", "")],
+        )
+        .await;
+        assert_eq!(requests[1], ("POST /elsewhere".into(), "body".into()));
+        assert!(result
+            .unwrap_err()
+            .contains("the HTTP client followed a redirect to http://"));
+    }
+
+    #[test]
+    fn final_errors() {
+        let url = Url::parse("http://localhost/").unwrap();
+        assert!(is_final(&RedirectError::FollowedByClient(url)));
+        assert!(!is_final(&RedirectError::TooMany));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (listener, url) = test_server::bind().await;
+        let server = tokio::spawn(async move {
+            // Read the request, but never answer it.
+            let (stream, _) = test_server::accept(&listener).await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(stream);
+        });
+        let push_client = PushClient {
+            client: PushClient::default_client(&Default::default())
+                .build()
+                .unwrap(),
+            url: Url::parse(&url).unwrap(),
+            headers: PushClient::default_headers(),
+            auth: None,
+            timeout: Some(Duration::from_millis(100)),
+        };
+        let error = push_client
+            .push(Bytes::from_static(b"body"), SystemTime::now())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "push request timed out after 100ms");
+        server.abort();
+
+        // Obtaining the token counts towards the timeout.
+        let push_client = PushClient {
+            auth: Some(Arc::new(Auth::bearer_fn(|| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok("token".into())
+            }))),
+            ..push_client
+        };
+        let start = Instant::now();
+        let error = push_client
+            .push(Bytes::from_static(b"body"), SystemTime::now())
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<TimedOut>().is_some());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use tracing_core::callsite::Identifier;
+use tracing_core::Level;
+
+use super::Error;
+use super::ErrorI;
+use super::EventMeta;
+use super::LevelMap;
+
+/// The number of independently locked parts of the callsite map, so that
+/// threads logging from different callsites rarely contend.
+const SHARDS: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Eq, Hash, PartialEq)]
+pub enum CallsiteKey {
+    Tracing(Identifier),
+    // Events converted from the `log` crate all share a few callsites, tell
+    // them apart by their source location instead.
+    Log(String, Option<String>, Option<u32>),
+}
+
+impl CallsiteKey {
+    fn of(meta: &EventMeta) -> CallsiteKey {
+        match meta {
+            EventMeta::Tracing(meta) => CallsiteKey::Tracing(meta.callsite()),
+            EventMeta::Log {
+                target, file, line, ..
+            } => CallsiteKey::Log(target.clone(), file.clone(), *line),
+        }
+    }
+}
+
+struct CallsiteState {
+    /// The metadata of the first event, to report suppressed events.
+    meta: EventMeta,
+    tokens: f64,
+    last_refill: SystemTime,
+    suppressed: u64,
+    last_summary: SystemTime,
+}
+
+impl CallsiteState {
+    fn refill(&mut self, bucket: TokenBucket, now: SystemTime) {
+        // The clock might go backwards, don't count the time in between
+        // twice.
+        if let Ok(elapsed) = now.duration_since(self.last_refill) {
+            self.tokens =
+                (self.tokens + elapsed.as_secs_f64() * bucket.per_second).min(bucket.burst);
+            self.last_refill = now;
+        }
+    }
+    fn summary_due(&self, now: SystemTime, interval: Duration) -> bool {
+        self.suppressed != 0
+            && now.duration_since(self.last_summary).unwrap_or_default() >= interval
+    }
+    fn take_summary(&mut self, now: SystemTime) -> u64 {
+        self.last_summary = now;
+        std::mem::take(&mut self.suppressed)
+    }
+}
+
+pub struct Decision {
+    pub keep: bool,
+    /// Number of suppressed events that should be reported now.
+    pub summary: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct RateLimits {
+    buckets: LevelMap<Option<TokenBucket>>,
+    sample_ratios: LevelMap<f64>,
+    summary_interval: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            buckets: LevelMap::from_fn(|_| None),
+            sample_ratios: LevelMap::from_fn(|_| 1.0),
+            summary_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn set_bucket(&mut self, level: Option<Level>, bucket: TokenBucket) -> Result<(), Error> {
+        // Such buckets would never let any event through.
+        if !(bucket.per_second.is_finite() && bucket.per_second > 0.0 && bucket.burst >= 1.0) {
+            return Err(Error(ErrorI::InvalidRateLimit(
+                bucket.per_second,
+                bucket.burst,
+            )));
+        }
+        match level {
+            Some(level) => self.buckets[level] = Some(bucket),
+            None => self.buckets.values_mut().for_each(|b| *b = Some(bucket)),
+        }
+        Ok(())
+    }
+    pub fn set_sample_ratio(&mut self, level: Option<Level>, ratio: f64) {
+        let ratio = ratio.clamp(0.0, 1.0);
+        match level {
+            Some(level) => self.sample_ratios[level] = ratio,
+            None => self.sample_ratios.values_mut().for_each(|r| *r = ratio),
+        }
+    }
+    pub fn set_summary_interval(&mut self, interval: Duration) {
+        self.summary_interval = interval;
+    }
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    enabled: bool,
+    shards: Vec<Mutex<HashMap<CallsiteKey, CallsiteState>>>,
+    hasher: RandomState,
+    rng: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        let enabled = limits.buckets.values().any(|b| b.is_some())
+            || limits.sample_ratios.values().any(|&r| r < 1.0);
+        RateLimiter {
+            limits,
+            enabled,
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            rng: AtomicU64::new(RandomState::new().build_hasher().finish()),
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    /// How often to check for suppressed events to report, if events are
+    /// rate limited at all.
+    pub fn summary_interval(&self) -> Option<Duration> {
+        Some(self.limits.summary_interval)
+            .filter(|_| self.limits.buckets.values().any(|b| b.is_some()))
+    }
+    /// Returns a uniformly distributed number in `[0, 1)`.
+    fn random(&self) -> f64 {
+        // SplitMix64, see
+        // <https://prng.di.unimi.it/splitmix64.c>.
+        let mut z = self
+            .rng
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+    pub fn check(&self, meta: &EventMeta, now: SystemTime) -> Decision {
+        let level = meta.level();
+        let ratio = self.limits.sample_ratios[level];
+        if ratio < 1.0 && self.random() >= ratio {
+            return Decision {
+                keep: false,
+                summary: None,
+            };
+        }
+        let bucket = match self.limits.buckets[level] {
+            Some(bucket) => bucket,
+            None => {
+                return Decision {
+                    keep: true,
+                    summary: None,
+                }
+            }
+        };
+        self.check_bucket(bucket, meta, now)
+    }
+    fn check_bucket(&self, bucket: TokenBucket, meta: &EventMeta, now: SystemTime) -> Decision {
+        let key = CallsiteKey::of(meta);
+        let shard = &self.shards[self.hasher.hash_one(&key) as usize % SHARDS];
+        let mut callsites = shard.lock().unwrap();
+        let state = callsites.entry(key).or_insert_with(|| CallsiteState {
+            meta: meta.clone(),
+            tokens: bucket.burst,
+            last_refill: now,
+            suppressed: 0,
+            last_summary: now,
+        });
+        state.refill(bucket, now);
+        let keep = state.tokens >= 1.0;
+        if keep {
+            state.tokens -= 1.0;
+        } else {
+            state.suppressed += 1;
+        }
+        let report =
+            state.suppressed != 0 && (keep || state.summary_due(now, self.limits.summary_interval));
+        let summary = if report {
+            Some(state.take_summary(now))
+        } else {
+            None
+        };
+        Decision { keep, summary }
+    }
+    /// Take the numbers of suppressed events that are due to be reported,
+    /// for callsites that stopped logging while being limited. With `all`,
+    /// take them regardless of when they were last reported.
+    ///
+    /// Callsites that have refilled their bucket are forgotten, so that the
+    /// state doesn't grow with callsites that only logged once.
+    pub fn take_due_summaries(&self, now: SystemTime, all: bool) -> Vec<(EventMeta, u64)> {
+        let interval = if all {
+            Duration::ZERO
+        } else {
+            self.limits.summary_interval
+        };
+        let mut result = Vec::new();
+        for shard in &self.shards {
+            shard.lock().unwrap().retain(|_, state| {
+                if state.summary_due(now, interval) {
+                    result.push((state.meta.clone(), state.take_summary(now)));
+                }
+                match self.limits.buckets[state.meta.level()] {
+                    Some(bucket) => {
+                        state.refill(bucket, now);
+                        state.suppressed != 0 || state.tokens < bucket.burst
+                    }
+                    None => false,
+                }
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimiter;
+    use super::RateLimits;
+    use super::TokenBucket;
+    use crate::EventMeta;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+    use tracing_core::Level;
+
+    fn meta(level: Level, line: u32) -> EventMeta {
+        EventMeta::Log {
+            level,
+            target: "target".into(),
+            module_path: None,
+            file: Some("src/lib.rs".into()),
+            line: Some(line),
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        assert!(!limiter.is_enabled());
+        assert_eq!(limiter.summary_interval(), None);
+        for _ in 0..1000 {
+            assert!(limiter.check(&meta(Level::ERROR, 1), UNIX_EPOCH).keep);
+        }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket {
+            per_second: 0.5,
+            burst: 2.0,
+        };
+        let mut limits = RateLimits::default();
+        limits.set_bucket(None, bucket).unwrap();
+        for (per_second, burst) in [(0.0, 1.0), (-1.0, 1.0), (f64::NAN, 1.0), (1.0, 0.0)] {
+            let invalid = TokenBucket { per_second, burst };
+            assert!(limits.set_bucket(None, invalid).is_err());
+        }
+        limits.set_summary_interval(Duration::from_secs(1));
+        let limiter = RateLimiter::new(limits);
+        let meta = meta(Level::INFO, 1);
+        let check =
+            |ms| limiter.check_bucket(bucket, &meta, UNIX_EPOCH + Duration::from_millis(ms));
+        let decision = |ms| {
+            let d = check(ms);
+            (d.keep, d.summary)
+        };
+
+        assert_eq!(decision(0), (true, None));
+        assert_eq!(decision(0), (true, None));
+        assert_eq!(decision(0), (false, None));
+        assert_eq!(decision(500), (false, None));
+        // Still flooding, report periodically.
+        assert_eq!(decision(1000), (false, Some(3)));
+        assert_eq!(decision(1500), (false, None));
+        // A token is available again, report the suppressed events.
+        assert_eq!(decision(2000), (true, Some(1)));
+        assert_eq!(decision(2000), (false, None));
+        // The clock went backwards.
+        assert_eq!(decision(0), (false, None));
+    }
+
+    #[test]
+    fn due_summaries() {
+        let mut limits = RateLimits::default();
+        limits
+            .set_bucket(
+                Some(Level::WARN),
+                TokenBucket {
+                    per_second: 1.0,
+                    burst: 1.0,
+                },
+            )
+            .unwrap();
+        limits.set_summary_interval(Duration::from_secs(10));
+        let limiter = RateLimiter::new(limits);
+        assert_eq!(limiter.summary_interval(), Some(Duration::from_secs(10)));
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let flooding = meta(Level::WARN, 1);
+        let quiet = meta(Level::WARN, 2);
+        assert!(limiter.check(&quiet, at(0)).keep);
+        for _ in 0..5 {
+            limiter.check(&flooding, at(0));
+        }
+        // The flood stopped, report it once the interval has passed.
+        assert!(limiter.take_due_summaries(at(9), false).is_empty());
+        let due = limiter.take_due_summaries(at(10), false);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0.line(), Some(1));
+        assert_eq!(due[0].1, 4);
+        assert!(limiter.take_due_summaries(at(20), false).is_empty());
+        limiter.check(&flooding, at(20));
+        limiter.check(&flooding, at(20));
+        let due = limiter.take_due_summaries(at(20), true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1, 1);
+        assert!(limiter.take_due_summaries(at(40), false).is_empty());
+        // Both callsites refilled their buckets and were forgotten.
+        let shards: usize = limiter.shards.iter().map(|s| s.lock().unwrap().len()).sum();
+        assert_eq!(shards, 0);
+    }
+
+    #[test]
+    fn sampling() {
+        let mut limits = RateLimits::default();
+        limits.set_sample_ratio(Some(Level::DEBUG), 0.25);
+        limits.set_sample_ratio(Some(Level::TRACE), 0.0);
+        let limiter = RateLimiter::new(limits);
+        assert!(limiter.is_enabled());
+        let check = |level| limiter.check(&meta(level, 1), UNIX_EPOCH).keep;
+        let kept = (0..10000).filter(|_| check(Level::DEBUG)).count();
+        assert!((2000..3000).contains(&kept), "{}", kept);
+        assert!((0..100).all(|_| !check(Level::TRACE)));
+        assert!((0..100).all(|_| check(Level::INFO)));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::path::Path;
+use std::process;
+use std::time::SystemTime;
+
+/// Information about the host or process that can be attached to all log
+/// records, see [`Builder::resource`](crate::Builder::resource).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Resource {
+    /// The hostname of the machine, with the key `host`.
+    Hostname,
+    /// The file name of the running executable without extension, with the
+    /// key `process`.
+    ProcessName,
+    /// The process ID, with the key `pid`.
+    Pid,
+    /// The version of the executable, with the key `version`.
+    ///
+    /// This crate can't know the version of the application using it, so it
+    /// needs to be passed in, typically as `env!("CARGO_PKG_VERSION")`.
+    ExecutableVersion(&'static str),
+    /// A random ID generated when the resource is added, with the key
+    /// `instance_id`. It distinguishes between restarts of the same process
+    /// on the same host.
+    InstanceId,
+}
+
+/// Where to attach a [`Resource`] or [`BuildInfo`](crate::BuildInfo).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResourceTarget {
+    /// As a label, see [`Builder::label`](crate::Builder::label).
+    ///
+    /// Only use this for values with low cardinality. The process ID and the
+    /// instance ID change on each restart and create new streams.
+    Label,
+    /// As an extra field, see
+    /// [`Builder::extra_field`](crate::Builder::extra_field).
+    ExtraField,
+}
+
+impl Resource {
+    /// The label key or extra field name the resource is attached as.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Resource::Hostname => "host",
+            Resource::ProcessName => "process",
+            Resource::Pid => "pid",
+            Resource::ExecutableVersion(_) => "version",
+            Resource::InstanceId => "instance_id",
+        }
+    }
+    /// Detect the value of the resource, returns `None` if it isn't
+    /// available.
+    pub(crate) fn detect(&self) -> Option<serde_json::Value> {
+        Some(match self {
+            Resource::Hostname => gethostname::gethostname().into_string().ok()?.into(),
+            Resource::ProcessName => process_name()?.into(),
+            Resource::Pid => process::id().into(),
+            Resource::ExecutableVersion(version) => (*version).into(),
+            Resource::InstanceId => instance_id().into(),
+        })
+    }
+}
+
+fn process_name() -> Option<String> {
+    let from_path = |path: &Path| Some(path.file_stem()?.to_str()?.to_owned());
+    env::current_exe()
+        .ok()
+        .and_then(|exe| from_path(&exe))
+        .or_else(|| from_path(Path::new(&env::args_os().next()?)))
+        .filter(|name| !name.is_empty())
+}
+
+/// Generate a random version 4 UUID.
+fn instance_id() -> String {
+    // `RandomState` is seeded with random keys from the operating system.
+    let random_u64 = |i: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(i);
+        hasher.write_u32(process::id());
+        if let Ok(d) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(d.as_nanos());
+        }
+        hasher.finish()
+    };
+    let hi = random_u64(0) & !0xf000 | 0x4000;
+    let lo = random_u64(1) & !(0xc000 << 48) | 0x8000 << 48;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        hi >> 16 & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::instance_id;
+    use super::Resource;
+    use serde_json::json;
+
+    #[test]
+    fn instance_id_format() {
+        let id = instance_id();
+        assert_eq!(id.len(), 36);
+        let groups: Vec<_> = id.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert!(id.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit()));
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(id, instance_id());
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Resource::Pid.detect(), Some(json!(std::process::id())));
+        assert_eq!(
+            Resource::ExecutableVersion("1.2.3").detect(),
+            Some(json!("1.2.3")),
+        );
+        let process = Resource::ProcessName.detect().unwrap();
+        assert!(process.as_str().unwrap().starts_with("tracing_loki"));
+        assert!(Resource::Hostname.detect().unwrap().is_string());
+    }
+}
@@ -0,0 +1,73 @@
+//! A minimal HTTP/1.1 server for tests, standing in for Loki or a token
+//! endpoint.
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+/// Bind to a free port on localhost, returning the listener and its URL.
+pub async fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (listener, url)
+}
+
+/// A received request, with lowercase header names.
+pub struct Request {
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (n, value) = line.split_once(": ")?;
+            Some(value).filter(|_| n.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+/// Accept a connection and read a request from it.
+pub async fn accept(listener: &TcpListener) -> (TcpStream, Request) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "connection closed mid-request");
+        data.extend_from_slice(&buf[..n]);
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8(data[..end].to_vec()).unwrap();
+            let mut request = Request {
+                head,
+                body: Vec::new(),
+            };
+            let len = request
+                .header("content-length")
+                .map_or(0, |l| l.parse().unwrap());
+            if data.len() >= end + 4 + len {
+                request.body = data[end + 4..end + 4 + len].to_vec();
+                return (stream, request);
+            }
+        }
+    }
+}
+
+/// Send a response with the given status line and body, closing the
+/// connection.
+pub async fn respond(stream: TcpStream, status: &str, body: &str) {
+    respond_with_headers(stream, status, "", body).await
+}
+
+/// Like [`respond`], with additional `headers`, each terminated by `\r\n`.
+pub async fn respond_with_headers(mut stream: TcpStream, status: &str, headers: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body,
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+}
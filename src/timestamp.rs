@@ -0,0 +1,313 @@
+use serde::Deserialize;
+use std::cmp;
+use std::fmt::Write as _;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::event::FieldValue;
+
+/// How the value of the field set with
+/// [`Builder::timestamp_field`](crate::Builder::timestamp_field) is
+/// interpreted.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// An RFC 3339 timestamp like `2023-08-01T12:34:56.789+02:00`.
+    Rfc3339,
+    /// Seconds since the Unix epoch, possibly fractional.
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch.
+    UnixMillis,
+    /// Nanoseconds since the Unix epoch.
+    UnixNanos,
+}
+
+impl TimestampFormat {
+    fn nanos_per_unit(self) -> i128 {
+        match self {
+            TimestampFormat::Rfc3339 => unreachable!(),
+            TimestampFormat::UnixSeconds => 1_000_000_000,
+            TimestampFormat::UnixMillis => 1_000_000,
+            TimestampFormat::UnixNanos => 1,
+        }
+    }
+    /// Parse a field value, returns `None` if it doesn't match the format.
+    pub fn parse(self, value: &FieldValue) -> Option<SystemTime> {
+        if self == TimestampFormat::Rfc3339 {
+            return match value {
+                FieldValue::Str(s) => parse_rfc3339(s),
+                _ => None,
+            };
+        }
+        let unit = self.nanos_per_unit();
+        let nanos = match *value {
+            FieldValue::Bool(_) => None,
+            FieldValue::I64(v) => i128::from(v).checked_mul(unit),
+            FieldValue::U64(v) => i128::from(v).checked_mul(unit),
+            FieldValue::F64(v) => float_nanos(v, unit),
+            FieldValue::Str(ref s) => match s.parse::<i64>() {
+                Ok(v) => i128::from(v).checked_mul(unit),
+                Err(_) => float_nanos(s.parse().ok()?, unit),
+            },
+        };
+        from_unix_nanos(nanos?)
+    }
+}
+
+fn float_nanos(value: f64, unit: i128) -> Option<i128> {
+    let nanos = (value * unit as f64).round();
+    if !nanos.is_finite() || nanos.abs() >= 1e30 {
+        return None;
+    }
+    Some(nanos as i128)
+}
+
+fn from_unix_nanos(nanos: i128) -> Option<SystemTime> {
+    let duration = |n: u128| {
+        let secs = u64::try_from(n / 1_000_000_000).ok()?;
+        Some(Duration::new(secs, (n % 1_000_000_000) as u32))
+    };
+    if nanos >= 0 {
+        UNIX_EPOCH.checked_add(duration(nanos.unsigned_abs())?)
+    } else {
+        UNIX_EPOCH.checked_sub(duration(nanos.unsigned_abs())?)
+    }
+}
+
+/// Convert a (year, month, day) civil date to days since 1970-01-01.
+///
+/// Algorithm from <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) civil date.
+///
+/// Algorithm from <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Format `time` as an RFC 3339 timestamp in UTC with nanosecond precision,
+/// like `2023-08-01T12:34:56.123456789Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs_of_day = secs.rem_euclid(86400);
+    let mut result = String::with_capacity(30);
+    write!(
+        result,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        nanos,
+    )
+    .unwrap();
+    result
+}
+
+/// Parse an RFC 3339 timestamp like `2023-08-01T12:34:56.123456789Z` or
+/// `2023-08-01T14:34:56+02:00`.
+///
+/// Fractional seconds beyond nanosecond precision are truncated.
+pub fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    fn number(s: &[u8]) -> Option<u32> {
+        if s.is_empty() || !s.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        Some(s.iter().fold(0, |n, &d| n * 10 + u32::from(d - b'0')))
+    }
+    let s = s.as_bytes();
+    if s.len() < 20
+        || s[4] != b'-'
+        || s[7] != b'-'
+        || !matches!(s[10], b'T' | b't' | b' ')
+        || s[13] != b':'
+        || s[16] != b':'
+    {
+        return None;
+    }
+    let year = number(&s[0..4])?;
+    let month = number(&s[5..7])?;
+    let day = number(&s[8..10])?;
+    let hour = number(&s[11..13])?;
+    let minute = number(&s[14..16])?;
+    let second = number(&s[17..19])?;
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if rest[0] == b'.' {
+        let digits = rest[1..].iter().take_while(|d| d.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        let used = cmp::min(digits, 9);
+        nanos = number(&rest[1..1 + used])? * 10u32.pow(9 - used as u32);
+        rest = &rest[1 + digits..];
+    }
+    let offset: i64 = match rest {
+        b"Z" | b"z" => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let hours = number(&[*h1, *h2])?;
+            let minutes = number(&[*m1, *m2])?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = i64::from(hours * 3600 + minutes * 60);
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+    // Leap seconds are allowed as `60`.
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year.into(), month, day);
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year.into(), month, day) {
+        return None;
+    }
+    let secs = days * 86400 + i64::from(hour * 3600 + minute * 60 + second) - offset;
+    from_unix_nanos(i128::from(secs) * 1_000_000_000 + i128::from(nanos))
+}
+
+#[cfg(test)]
+mod test {
+    use super::format_rfc3339;
+    use super::parse_rfc3339;
+    use super::TimestampFormat;
+    use crate::event::FieldValue;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn format() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::new(1690893296, 123456789)),
+            "2023-08-01T12:34:56.123456789Z",
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000000000Z",
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH - Duration::from_nanos(1)),
+            "1969-12-31T23:59:59.999999999Z",
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let time = UNIX_EPOCH + Duration::new(1690893296, 123456789);
+        assert_eq!(parse_rfc3339("2023-08-01T12:34:56.123456789Z"), Some(time));
+        assert_eq!(
+            parse_rfc3339("2023-08-01t14:34:56.1234567891+02:00"),
+            Some(time)
+        );
+        assert_eq!(
+            parse_rfc3339("2023-08-01 10:04:56.5-02:30"),
+            Some(UNIX_EPOCH + Duration::from_millis(1690893296500)),
+        );
+        assert_eq!(
+            parse_rfc3339("1969-12-31T23:59:59Z"),
+            Some(UNIX_EPOCH - Duration::from_secs(1)),
+        );
+        for invalid in [
+            "",
+            "2023-08-01",
+            "2023-08-01T12:34:56",
+            "2023-08-01T12:34:56.Z",
+            "2023-08-01T12:34:56+0200",
+            "2023-02-29T12:34:56Z",
+            "2023-13-01T12:34:56Z",
+            "2023-08-01T24:00:00Z",
+            "2023-08-01T12:34:56Zjunk",
+            "2023-08-01T12:3a:56Z",
+        ] {
+            assert_eq!(parse_rfc3339(invalid), None, "{}", invalid);
+        }
+        for (a, b) in [
+            (format_rfc3339(time), time),
+            (format_rfc3339(UNIX_EPOCH), UNIX_EPOCH),
+        ] {
+            assert_eq!(parse_rfc3339(&a), Some(b));
+        }
+    }
+
+    #[test]
+    fn parse_unix() {
+        let time = UNIX_EPOCH + Duration::from_millis(1690893296500);
+        let cases = [
+            (TimestampFormat::UnixSeconds, FieldValue::F64(1690893296.5)),
+            (
+                TimestampFormat::UnixSeconds,
+                FieldValue::Str("1690893296.5".into()),
+            ),
+            (TimestampFormat::UnixMillis, FieldValue::U64(1690893296500)),
+            (
+                TimestampFormat::UnixMillis,
+                FieldValue::Str("1690893296500".into()),
+            ),
+            (
+                TimestampFormat::UnixNanos,
+                FieldValue::I64(1690893296500000000),
+            ),
+            (
+                TimestampFormat::Rfc3339,
+                FieldValue::Str("2023-08-01T12:34:56.5Z".into()),
+            ),
+        ];
+        for (format, value) in cases {
+            assert_eq!(format.parse(&value), Some(time), "{:?}", value);
+        }
+        assert_eq!(
+            TimestampFormat::UnixSeconds.parse(&FieldValue::I64(-1)),
+            Some(UNIX_EPOCH - Duration::from_secs(1)),
+        );
+        assert_eq!(
+            TimestampFormat::UnixSeconds.parse(&FieldValue::Bool(true)),
+            None
+        );
+        assert_eq!(
+            TimestampFormat::UnixSeconds.parse(&FieldValue::F64(f64::NAN)),
+            None
+        );
+        assert_eq!(
+            TimestampFormat::UnixMillis.parse(&FieldValue::Str("soon".into())),
+            None
+        );
+        assert_eq!(TimestampFormat::Rfc3339.parse(&FieldValue::U64(0)), None);
+    }
+}